@@ -0,0 +1,6 @@
+-- Track which device each session belongs to so users can manage their logins
+ALTER TABLE sessions
+    ADD COLUMN device_name VARCHAR(255),
+    ADD COLUMN user_agent TEXT,
+    ADD COLUMN ip_address VARCHAR(45),
+    ADD COLUMN last_active_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW();
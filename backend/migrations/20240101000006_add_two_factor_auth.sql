@@ -0,0 +1,18 @@
+-- Optional TOTP second factor. The secret is written at enrollment and only
+-- enforced once totp_enabled_at is set by confirming a first code.
+ALTER TABLE users
+    ADD COLUMN totp_secret VARCHAR(64),
+    ADD COLUMN totp_enabled_at TIMESTAMP WITH TIME ZONE,
+    ADD COLUMN totp_last_used_step BIGINT; -- time step of the last accepted code, to block replays
+
+-- Create recovery_codes table: single-use fallbacks for a lost authenticator
+CREATE TABLE recovery_codes (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    code_hash VARCHAR(64) NOT NULL, -- SHA-256 hex of the normalized code
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    used_at TIMESTAMP WITH TIME ZONE
+);
+
+-- Create indexes
+CREATE INDEX idx_recovery_codes_user_id ON recovery_codes(user_id);
@@ -0,0 +1,28 @@
+-- Create login throttle scope enum
+CREATE TYPE login_throttle_scope AS ENUM ('account', 'ip');
+
+-- Create login_failures table: recent failed logins per email and per client IP
+CREATE TABLE login_failures (
+    scope login_throttle_scope NOT NULL,
+    key VARCHAR(255) NOT NULL, -- normalized email or IP address
+    failure_count INTEGER NOT NULL DEFAULT 0,
+    last_failure_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    blocked_until TIMESTAMP WITH TIME ZONE,
+    PRIMARY KEY (scope, key)
+);
+
+-- Create lockout_events table: audit trail of lockouts for administrators
+CREATE TABLE lockout_events (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    scope login_throttle_scope NOT NULL,
+    key VARCHAR(255) NOT NULL,
+    user_id UUID REFERENCES users(id) ON DELETE SET NULL, -- the targeted account, if it exists
+    ip_address VARCHAR(45), -- client that triggered the lockout
+    failure_count INTEGER NOT NULL,
+    locked_until TIMESTAMP WITH TIME ZONE NOT NULL,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+-- Create indexes
+CREATE INDEX idx_lockout_events_user_id ON lockout_events(user_id, created_at DESC);
+CREATE INDEX idx_lockout_events_created_at ON lockout_events(created_at DESC);
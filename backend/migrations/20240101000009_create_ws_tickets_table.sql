@@ -0,0 +1,11 @@
+-- Create ws_tickets table: single-use credentials for opening a WebSocket,
+-- so the access token never has to appear in a URL
+CREATE TABLE ws_tickets (
+    ticket_hash VARCHAR(64) PRIMARY KEY, -- SHA-256 hex of the ticket
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    session_id UUID NOT NULL REFERENCES sessions(id) ON DELETE CASCADE,
+    expires_at TIMESTAMP WITH TIME ZONE NOT NULL
+);
+
+-- Create indexes
+CREATE INDEX idx_ws_tickets_expires_at ON ws_tickets(expires_at);
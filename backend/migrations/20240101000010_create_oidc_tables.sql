@@ -0,0 +1,25 @@
+-- Create user_identities table: accounts at external OpenID Connect providers
+-- linked to cam-chat users
+CREATE TABLE user_identities (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    issuer VARCHAR(255) NOT NULL,
+    subject VARCHAR(255) NOT NULL, -- the provider's `sub` claim
+    email VARCHAR(255) NOT NULL, -- email the provider reported at the last login
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    last_login_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    UNIQUE(issuer, subject)
+);
+
+-- Create oidc_login_states table: in-flight authorization requests, keyed by
+-- the `state` parameter round-tripped through the provider
+CREATE TABLE oidc_login_states (
+    state_hash VARCHAR(64) PRIMARY KEY, -- SHA-256 hex of the state
+    code_verifier VARCHAR(128) NOT NULL, -- PKCE verifier for the code exchange
+    nonce VARCHAR(64) NOT NULL, -- expected `nonce` claim in the ID token
+    expires_at TIMESTAMP WITH TIME ZONE NOT NULL
+);
+
+-- Create indexes
+CREATE INDEX idx_user_identities_user_id ON user_identities(user_id);
+CREATE INDEX idx_oidc_login_states_expires_at ON oidc_login_states(expires_at);
@@ -0,0 +1,29 @@
+-- Bots are users that act through API keys instead of logging in
+ALTER TABLE users
+    ADD COLUMN is_bot BOOLEAN NOT NULL DEFAULT false,
+    ADD COLUMN bot_owner_id UUID REFERENCES users(id) ON DELETE CASCADE;
+
+-- Create api_keys table: credentials a bot authenticates with
+CREATE TABLE api_keys (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    bot_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    name VARCHAR(255) NOT NULL,
+    key_prefix VARCHAR(16) NOT NULL, -- start of the key, so owners can tell keys apart
+    key_hash VARCHAR(64) UNIQUE NOT NULL, -- SHA-256 hex of the key
+    permissions TEXT[] NOT NULL, -- e.g. {read_messages,send_messages}
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    expires_at TIMESTAMP WITH TIME ZONE,
+    last_used_at TIMESTAMP WITH TIME ZONE,
+    revoked_at TIMESTAMP WITH TIME ZONE
+);
+
+-- Create api_key_chats table: the chats a key may be used in
+CREATE TABLE api_key_chats (
+    api_key_id UUID NOT NULL REFERENCES api_keys(id) ON DELETE CASCADE,
+    chat_id UUID NOT NULL REFERENCES chats(id) ON DELETE CASCADE,
+    PRIMARY KEY (api_key_id, chat_id)
+);
+
+-- Create indexes
+CREATE INDEX idx_users_bot_owner_id ON users(bot_owner_id);
+CREATE INDEX idx_api_keys_bot_id ON api_keys(bot_id);
@@ -0,0 +1,79 @@
+-- Direct chats are keyed by their two users, smaller id first, so each pair
+-- of users has at most one
+ALTER TABLE chats
+    ADD COLUMN direct_user_a UUID REFERENCES users(id) ON DELETE CASCADE,
+    ADD COLUMN direct_user_b UUID REFERENCES users(id) ON DELETE CASCADE,
+    ADD CONSTRAINT chats_direct_pair_ordered CHECK (direct_user_a < direct_user_b);
+
+-- Find the user pair of every existing direct chat. Bots added through API
+-- keys don't count towards the pair.
+CREATE TEMP TABLE direct_chat_pairs AS
+SELECT c.id AS chat_id,
+       MIN(cp.user_id::text)::uuid AS user_a,
+       MAX(cp.user_id::text)::uuid AS user_b,
+       c.created_at,
+       c.updated_at
+FROM chats c
+JOIN chat_participants cp ON cp.chat_id = c.id
+JOIN users u ON u.id = cp.user_id AND NOT u.is_bot
+WHERE c.is_group IS NOT TRUE
+GROUP BY c.id
+HAVING COUNT(*) = 2;
+
+-- Keep the oldest chat of each pair and merge the others into it
+CREATE TEMP TABLE direct_chat_merges AS
+SELECT chat_id,
+       user_a,
+       user_b,
+       updated_at,
+       FIRST_VALUE(chat_id) OVER (
+           PARTITION BY user_a, user_b ORDER BY created_at, chat_id
+       ) AS canonical_id
+FROM direct_chat_pairs;
+
+UPDATE messages m
+SET chat_id = dm.canonical_id
+FROM direct_chat_merges dm
+WHERE m.chat_id = dm.chat_id AND dm.chat_id <> dm.canonical_id;
+
+INSERT INTO chat_participants (chat_id, user_id, joined_at, is_admin)
+SELECT dm.canonical_id, cp.user_id, cp.joined_at, cp.is_admin
+FROM chat_participants cp
+JOIN direct_chat_merges dm ON dm.chat_id = cp.chat_id AND dm.chat_id <> dm.canonical_id
+ON CONFLICT DO NOTHING;
+
+INSERT INTO api_key_chats (api_key_id, chat_id)
+SELECT akc.api_key_id, dm.canonical_id
+FROM api_key_chats akc
+JOIN direct_chat_merges dm ON dm.chat_id = akc.chat_id AND dm.chat_id <> dm.canonical_id
+ON CONFLICT DO NOTHING;
+
+UPDATE chats c
+SET updated_at = latest.updated_at
+FROM (
+    SELECT canonical_id, MAX(updated_at) AS updated_at
+    FROM direct_chat_merges
+    GROUP BY canonical_id
+) latest
+WHERE c.id = latest.canonical_id AND latest.updated_at > c.updated_at;
+
+DELETE FROM chats c
+USING direct_chat_merges dm
+WHERE c.id = dm.chat_id AND dm.chat_id <> dm.canonical_id;
+
+UPDATE chats c
+SET direct_user_a = dm.user_a, direct_user_b = dm.user_b
+FROM direct_chat_merges dm
+WHERE c.id = dm.chat_id AND dm.chat_id = dm.canonical_id;
+
+DROP TABLE direct_chat_merges;
+DROP TABLE direct_chat_pairs;
+
+-- Only groups have admins; direct chats used to mark their creator as one
+UPDATE chat_participants cp
+SET is_admin = false
+FROM chats c
+WHERE c.id = cp.chat_id AND c.is_group IS NOT TRUE AND cp.is_admin;
+
+-- Create indexes
+CREATE UNIQUE INDEX idx_chats_direct_pair ON chats(direct_user_a, direct_user_b);
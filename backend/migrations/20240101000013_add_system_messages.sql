@@ -0,0 +1,2 @@
+-- System messages record events such as members joining or leaving a group
+ALTER TYPE message_type ADD VALUE 'system';
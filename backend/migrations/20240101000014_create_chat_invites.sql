@@ -0,0 +1,33 @@
+-- Create chat_invites table: links that let people join a group
+CREATE TABLE chat_invites (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    chat_id UUID NOT NULL REFERENCES chats(id) ON DELETE CASCADE,
+    created_by UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    code_prefix VARCHAR(16) NOT NULL, -- start of the code, so admins can tell links apart
+    code_hash VARCHAR(64) UNIQUE NOT NULL, -- SHA-256 hex of the code
+    requires_approval BOOLEAN NOT NULL DEFAULT false, -- joining creates a request for admins to approve
+    max_uses INTEGER, -- NULL for unlimited
+    use_count INTEGER NOT NULL DEFAULT 0,
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    expires_at TIMESTAMP WITH TIME ZONE,
+    revoked_at TIMESTAMP WITH TIME ZONE
+);
+
+-- Create join request status enum
+CREATE TYPE join_request_status AS ENUM ('pending', 'approved', 'declined');
+
+-- Create chat_join_requests table: people waiting to join through an approval-mode invite
+CREATE TABLE chat_join_requests (
+    id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+    chat_id UUID NOT NULL REFERENCES chats(id) ON DELETE CASCADE,
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    invite_id UUID REFERENCES chat_invites(id) ON DELETE SET NULL,
+    status join_request_status NOT NULL DEFAULT 'pending',
+    created_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    decided_by UUID REFERENCES users(id) ON DELETE SET NULL,
+    decided_at TIMESTAMP WITH TIME ZONE
+);
+
+-- Create indexes
+CREATE INDEX idx_chat_invites_chat_id ON chat_invites(chat_id);
+CREATE UNIQUE INDEX idx_chat_join_requests_pending ON chat_join_requests(chat_id, user_id) WHERE status = 'pending';
@@ -0,0 +1,7 @@
+-- Group info and what ordinary members may do; admins can always do everything
+ALTER TABLE chats
+    ADD COLUMN description TEXT,
+    ADD COLUMN avatar_url TEXT,
+    ADD COLUMN only_admins_send_messages BOOLEAN NOT NULL DEFAULT false,
+    ADD COLUMN only_admins_edit_info BOOLEAN NOT NULL DEFAULT false,
+    ADD COLUMN only_admins_add_members BOOLEAN NOT NULL DEFAULT true;
@@ -0,0 +1,6 @@
+-- Each participant's own view of the chat
+ALTER TABLE chat_participants
+    ADD COLUMN is_archived BOOLEAN NOT NULL DEFAULT false,
+    ADD COLUMN pin_position INTEGER, -- NULL unless pinned; lower comes first
+    ADD COLUMN muted_until TIMESTAMP WITH TIME ZONE,
+    ADD COLUMN marked_unread BOOLEAN NOT NULL DEFAULT false;
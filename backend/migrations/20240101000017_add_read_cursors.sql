@@ -0,0 +1,7 @@
+-- How far each participant has read; later messages from others are unread
+ALTER TABLE chat_participants
+    ADD COLUMN last_read_message_id UUID REFERENCES messages(id) ON DELETE SET NULL,
+    ADD COLUMN last_read_at TIMESTAMP WITH TIME ZONE;
+
+-- Existing conversations start out read rather than with every message unread
+UPDATE chat_participants SET last_read_at = NOW();
@@ -0,0 +1,11 @@
+-- The newest message of each chat, so chat lists don't have to search for it
+ALTER TABLE chats
+    ADD COLUMN last_message_id UUID REFERENCES messages(id) ON DELETE SET NULL;
+
+UPDATE chats c
+SET last_message_id = (
+    SELECT m.id FROM messages m
+    WHERE m.chat_id = c.id
+    ORDER BY m.created_at DESC, m.id DESC
+    LIMIT 1
+);
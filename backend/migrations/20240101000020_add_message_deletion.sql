@@ -0,0 +1,14 @@
+-- Messages deleted for everyone stay as tombstones with their content
+-- cleared, so replies to them can still say what they replied to. reply_to
+-- is only cleared when the row itself goes, e.g. with its chat.
+ALTER TABLE messages
+    ADD COLUMN deleted_at TIMESTAMP WITH TIME ZONE,
+    ADD COLUMN deleted_by UUID REFERENCES users(id) ON DELETE SET NULL;
+
+-- Create hidden_messages table: messages a user deleted for themselves only
+CREATE TABLE hidden_messages (
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    message_id UUID NOT NULL REFERENCES messages(id) ON DELETE CASCADE,
+    hidden_at TIMESTAMP WITH TIME ZONE NOT NULL DEFAULT NOW(),
+    PRIMARY KEY (user_id, message_id)
+);
@@ -0,0 +1,3 @@
+-- Message history is paged by (created_at, id), with the id breaking ties
+DROP INDEX idx_messages_chat_id;
+CREATE INDEX idx_messages_chat_id ON messages(chat_id, created_at, id);
@@ -0,0 +1,7 @@
+-- Full-text search over message content. The 'simple' configuration doesn't
+-- stem, so it works the same for English, French and Pidgin messages
+ALTER TABLE messages
+    ADD COLUMN content_search TSVECTOR GENERATED ALWAYS AS (to_tsvector('simple', content)) STORED;
+
+-- Create indexes
+CREATE INDEX idx_messages_content_search ON messages USING GIN (content_search);
@@ -0,0 +1,91 @@
+use anyhow::Result;
+use axum::http::StatusCode;
+use chrono::{Duration, Utc};
+use uuid::Uuid;
+
+use super::{generate_token, hash_token};
+use crate::{models::bot::ApiKeyPermission, AppState};
+
+/// Every API key starts with this, which is how `auth_middleware` tells them
+/// apart from JWTs.
+pub const API_KEY_PREFIX: &str = "cck_";
+
+/// Characters of the key kept in the clear for display.
+const DISPLAYED_PREFIX_LEN: usize = 12;
+
+/// `last_used_at` is only written when older than this.
+const USAGE_RESOLUTION_SECS: i64 = 60;
+
+/// Routes that accept API keys. Every other protected route is for people
+/// only, so a leaked key can't reach account settings. Matched on the path
+/// alone, so every handler on these paths must check the key's `ApiKeyScope`.
+pub const API_KEY_ROUTES: &[&str] = &["/api/chats/:chat_id/messages", "/api/chats/:chat_id/messages/:message_id"];
+
+/// What the API key on the current request may do, set by `auth_middleware`
+/// alongside the bot's user ID. Absent for user logins.
+#[derive(Debug, Clone)]
+pub struct ApiKeyScope {
+    pub chat_ids: Vec<Uuid>,
+    pub permissions: Vec<ApiKeyPermission>,
+}
+
+impl ApiKeyScope {
+    /// Fails with `403` unless the key covers the chat and the permission.
+    pub fn require(&self, chat_id: Uuid, permission: ApiKeyPermission) -> Result<(), StatusCode> {
+        if self.chat_ids.contains(&chat_id) && self.permissions.contains(&permission) {
+            Ok(())
+        } else {
+            Err(StatusCode::FORBIDDEN)
+        }
+    }
+}
+
+/// Returns a new key and its displayed prefix.
+pub fn generate_api_key() -> (String, String) {
+    let key = format!("{}{}", API_KEY_PREFIX, generate_token());
+    let prefix = key[..DISPLAYED_PREFIX_LEN].to_string();
+    (key, prefix)
+}
+
+/// Looks up a live API key, returning the bot it belongs to and its scope.
+pub async fn authenticate_api_key(state: &AppState, api_key: &str) -> Result<Option<(Uuid, ApiKeyScope)>> {
+    let key = sqlx::query!(
+        r#"
+        SELECT k.id, k.bot_id, k.permissions, k.last_used_at,
+               ARRAY(SELECT chat_id FROM api_key_chats WHERE api_key_id = k.id) AS "chat_ids!"
+        FROM api_keys k
+        JOIN users u ON u.id = k.bot_id
+        WHERE k.key_hash = $1 AND k.revoked_at IS NULL
+          AND (k.expires_at IS NULL OR k.expires_at > NOW())
+          AND u.is_bot
+        "#,
+        hash_token(api_key)
+    )
+    .fetch_optional(state.db.pool())
+    .await?;
+
+    let Some(key) = key else {
+        return Ok(None);
+    };
+
+    let stale = key
+        .last_used_at
+        .map(|at| Utc::now() - at > Duration::seconds(USAGE_RESOLUTION_SECS))
+        .unwrap_or(true);
+    if stale {
+        sqlx::query!("UPDATE api_keys SET last_used_at = NOW() WHERE id = $1", key.id)
+            .execute(state.db.pool())
+            .await?;
+    }
+
+    let scope = ApiKeyScope {
+        chat_ids: key.chat_ids,
+        permissions: key
+            .permissions
+            .iter()
+            .filter_map(|permission| ApiKeyPermission::parse(permission))
+            .collect(),
+    };
+
+    Ok(Some((key.bot_id, scope)))
+}
@@ -0,0 +1,114 @@
+use anyhow::Result;
+use chrono::{Duration, Utc};
+use tracing::error;
+use uuid::Uuid;
+
+use super::{generate_token, hash_token};
+use crate::{mail::Email, models::email_token::EmailTokenPurpose, models::User, AppState};
+
+const PASSWORD_RESET_TTL_MINUTES: i64 = 60;
+const EMAIL_VERIFICATION_TTL_HOURS: i64 = 48;
+
+/// Creates a single-use token, invalidating any earlier unused ones for the
+/// same purpose so only the most recent email works.
+pub async fn issue_token(state: &AppState, user_id: Uuid, purpose: EmailTokenPurpose) -> Result<String> {
+    let token = generate_token();
+    let ttl = match purpose {
+        EmailTokenPurpose::PasswordReset => Duration::minutes(PASSWORD_RESET_TTL_MINUTES),
+        EmailTokenPurpose::EmailVerification => Duration::hours(EMAIL_VERIFICATION_TTL_HOURS),
+    };
+
+    let mut tx = state.db.pool().begin().await?;
+
+    sqlx::query!(
+        "UPDATE email_tokens SET used_at = NOW() WHERE user_id = $1 AND purpose = $2 AND used_at IS NULL",
+        user_id,
+        purpose as EmailTokenPurpose
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    sqlx::query!(
+        r#"
+        INSERT INTO email_tokens (user_id, purpose, token_hash, expires_at)
+        VALUES ($1, $2, $3, $4)
+        "#,
+        user_id,
+        purpose as EmailTokenPurpose,
+        hash_token(&token),
+        Utc::now() + ttl
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(token)
+}
+
+/// Marks the token used and returns its user, or `None` if it is unknown,
+/// expired, already used or meant for something else.
+pub async fn consume_token(state: &AppState, token: &str, purpose: EmailTokenPurpose) -> Result<Option<Uuid>> {
+    let user_id = sqlx::query_scalar!(
+        r#"
+        UPDATE email_tokens SET used_at = NOW()
+        WHERE token_hash = $1 AND purpose = $2 AND used_at IS NULL AND expires_at > NOW()
+        RETURNING user_id
+        "#,
+        hash_token(token),
+        purpose as EmailTokenPurpose
+    )
+    .fetch_optional(state.db.pool())
+    .await?;
+
+    Ok(user_id)
+}
+
+pub async fn send_verification_email(state: &AppState, user: &User) -> Result<()> {
+    let token = issue_token(state, user.id, EmailTokenPurpose::EmailVerification).await?;
+    let link = format!("{}/verify-email?token={}", state.config.app_base_url, token);
+
+    send_in_background(
+        state,
+        Email {
+            to: user.email.clone(),
+            subject: "Confirm your email address".to_string(),
+            body: format!(
+                "Hi {},\n\nConfirm your email address by opening this link:\n\n{}\n\nThe link expires in {} hours.",
+                user.name, link, EMAIL_VERIFICATION_TTL_HOURS
+            ),
+        },
+    );
+
+    Ok(())
+}
+
+pub async fn send_password_reset_email(state: &AppState, user: &User) -> Result<()> {
+    let token = issue_token(state, user.id, EmailTokenPurpose::PasswordReset).await?;
+    let link = format!("{}/reset-password?token={}", state.config.app_base_url, token);
+
+    send_in_background(
+        state,
+        Email {
+            to: user.email.clone(),
+            subject: "Reset your password".to_string(),
+            body: format!(
+                "Hi {},\n\nSomeone asked to reset the password for this account. If it was you, open this link:\n\n{}\n\nThe link expires in {} minutes. If you didn't ask for this, you can ignore this email.",
+                user.name, link, PASSWORD_RESET_TTL_MINUTES
+            ),
+        },
+    );
+
+    Ok(())
+}
+
+// Sending happens off the request so a slow relay can't stall it.
+fn send_in_background(state: &AppState, email: Email) {
+    let mailer = state.mailer.clone();
+    tokio::spawn(async move {
+        let to = email.to.clone();
+        if let Err(e) = mailer.send(email).await {
+            error!("Failed to send email to {}: {}", to, e);
+        }
+    });
+}
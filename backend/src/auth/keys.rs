@@ -0,0 +1,394 @@
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use jsonwebtoken::{
+    decode, decode_header, encode,
+    jwk::{
+        AlgorithmParameters, CommonParameters, EllipticCurve, Jwk, JwkSet, KeyAlgorithm,
+        OctetKeyPairParameters, OctetKeyPairType, PublicKeyUse, RSAKeyParameters, RSAKeyType,
+    },
+    Algorithm, DecodingKey, EncodingKey, Header, Validation,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use simple_asn1::ASN1Block;
+use std::path::{Path, PathBuf};
+
+/// Key ID used when the key set comes from a bare `JWT_SECRET`.
+const DEFAULT_KID: &str = "default";
+
+/// On-disk format of the file pointed to by `JWT_KEYS_FILE`.
+///
+/// ```json
+/// {
+///   "active_kid": "2024-06",
+///   "keys": [
+///     { "kid": "2024-06", "alg": "EdDSA", "private_key_path": "2024-06.pem", "public_key_path": "2024-06.pub.pem" },
+///     { "kid": "2024-01", "alg": "RS256", "public_key_path": "2024-01.pub.pem" }
+///   ]
+/// }
+/// ```
+///
+/// Relative paths are resolved against the directory containing the file.
+#[derive(Debug, Deserialize)]
+struct KeySetFile {
+    active_kid: String,
+    keys: Vec<KeyEntry>,
+}
+
+#[derive(Debug, Deserialize)]
+struct KeyEntry {
+    kid: String,
+    alg: Algorithm,
+    /// Shared secret for HMAC algorithms.
+    secret: Option<String>,
+    /// PEM private key; only needed for the key that signs new tokens.
+    private_key_path: Option<PathBuf>,
+    /// PEM public key (SPKI or PKCS#1 for RSA).
+    public_key_path: Option<PathBuf>,
+}
+
+struct SigningKey {
+    kid: String,
+    algorithm: Algorithm,
+    encoding_key: Option<EncodingKey>,
+    decoding_key: DecodingKey,
+    /// Published in the JWKS; `None` for symmetric keys.
+    public_jwk: Option<Jwk>,
+}
+
+/// The set of keys tokens are verified against, one of which is the active
+/// key used to sign new tokens. Rotating means adding a new key, making it
+/// active, and dropping the old one once its tokens have expired.
+pub struct JwtKeys {
+    active: usize,
+    keys: Vec<SigningKey>,
+}
+
+impl JwtKeys {
+    /// Loads keys from `JWT_KEYS_FILE` if set, otherwise uses `JWT_SECRET`
+    /// as a single HS256 key.
+    pub fn from_env() -> Result<Self> {
+        if let Ok(path) = std::env::var("JWT_KEYS_FILE") {
+            return Self::from_file(Path::new(&path));
+        }
+
+        let secret = std::env::var("JWT_SECRET")
+            .map_err(|_| anyhow!("either JWT_KEYS_FILE or JWT_SECRET must be set"))?;
+        Ok(Self::from_secret(&secret))
+    }
+
+    pub fn from_secret(secret: &str) -> Self {
+        Self {
+            active: 0,
+            keys: vec![SigningKey {
+                kid: DEFAULT_KID.to_string(),
+                algorithm: Algorithm::HS256,
+                encoding_key: Some(EncodingKey::from_secret(secret.as_bytes())),
+                decoding_key: DecodingKey::from_secret(secret.as_bytes()),
+                public_jwk: None,
+            }],
+        }
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("reading JWT key set {}", path.display()))?;
+        let file: KeySetFile = serde_json::from_str(&contents)
+            .with_context(|| format!("parsing JWT key set {}", path.display()))?;
+        let base_dir = path.parent().unwrap_or_else(|| Path::new("."));
+
+        let keys = file
+            .keys
+            .into_iter()
+            .map(|entry| {
+                let kid = entry.kid.clone();
+                load_key(entry, base_dir).with_context(|| format!("loading JWT key {kid:?}"))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let active = keys
+            .iter()
+            .position(|key| key.kid == file.active_kid)
+            .ok_or_else(|| anyhow!("active_kid {:?} is not in the key set", file.active_kid))?;
+        ensure!(
+            keys[active].encoding_key.is_some(),
+            "active key {:?} has no private key or secret",
+            file.active_kid
+        );
+
+        Ok(Self { active, keys })
+    }
+
+    /// Signs `claims` with the active key, setting `kid` in the header.
+    pub fn sign<T: Serialize>(&self, claims: &T) -> Result<String> {
+        let key = &self.keys[self.active];
+        let encoding_key = key
+            .encoding_key
+            .as_ref()
+            .ok_or_else(|| anyhow!("active key {:?} cannot sign", key.kid))?;
+
+        let mut header = Header::new(key.algorithm);
+        header.kid = Some(key.kid.clone());
+
+        Ok(encode(&header, claims, encoding_key)?)
+    }
+
+    /// Verifies a token against the key named by its `kid`. Tokens issued
+    /// before `kid` was set are tried against every key of the same algorithm.
+    pub fn verify<T: DeserializeOwned>(&self, token: &str) -> Result<T> {
+        let header = decode_header(token)?;
+
+        let candidates: Vec<&SigningKey> = match &header.kid {
+            Some(kid) => self.keys.iter().filter(|key| &key.kid == kid).collect(),
+            None => self.keys.iter().filter(|key| key.algorithm == header.alg).collect(),
+        };
+
+        let mut last_error = anyhow!("no key matches token header");
+        for key in candidates {
+            // Pin the algorithm to the key's so a token can't pick its own
+            let validation = Validation::new(key.algorithm);
+            match decode::<T>(token, &key.decoding_key, &validation) {
+                Ok(data) => return Ok(data.claims),
+                Err(e) => last_error = e.into(),
+            }
+        }
+
+        Err(last_error)
+    }
+
+    /// Public keys for `/.well-known/jwks.json`. Symmetric keys are never
+    /// published.
+    pub fn jwks(&self) -> JwkSet {
+        JwkSet {
+            keys: self
+                .keys
+                .iter()
+                .filter_map(|key| key.public_jwk.clone())
+                .collect(),
+        }
+    }
+}
+
+fn load_key(entry: KeyEntry, base_dir: &Path) -> Result<SigningKey> {
+    let read_pem = |path: &PathBuf| {
+        let path = base_dir.join(path);
+        std::fs::read(&path).with_context(|| format!("reading {}", path.display()))
+    };
+
+    match entry.alg {
+        Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => {
+            let secret = entry
+                .secret
+                .ok_or_else(|| anyhow!("HMAC keys need a `secret`"))?;
+            Ok(SigningKey {
+                kid: entry.kid,
+                algorithm: entry.alg,
+                encoding_key: Some(EncodingKey::from_secret(secret.as_bytes())),
+                decoding_key: DecodingKey::from_secret(secret.as_bytes()),
+                public_jwk: None,
+            })
+        }
+        Algorithm::RS256
+        | Algorithm::RS384
+        | Algorithm::RS512
+        | Algorithm::PS256
+        | Algorithm::PS384
+        | Algorithm::PS512
+        | Algorithm::EdDSA => {
+            let public_pem = entry
+                .public_key_path
+                .as_ref()
+                .map(read_pem)
+                .transpose()?
+                .ok_or_else(|| anyhow!("asymmetric keys need a `public_key_path`"))?;
+            let private_pem = entry.private_key_path.as_ref().map(read_pem).transpose()?;
+
+            let is_rsa = entry.alg != Algorithm::EdDSA;
+            let decoding_key = if is_rsa {
+                DecodingKey::from_rsa_pem(&public_pem)?
+            } else {
+                DecodingKey::from_ed_pem(&public_pem)?
+            };
+            let encoding_key = private_pem
+                .map(|pem| {
+                    if is_rsa {
+                        EncodingKey::from_rsa_pem(&pem)
+                    } else {
+                        EncodingKey::from_ed_pem(&pem)
+                    }
+                })
+                .transpose()?;
+
+            let public_jwk = Jwk {
+                common: CommonParameters {
+                    public_key_use: Some(PublicKeyUse::Signature),
+                    key_algorithm: Some(format!("{:?}", entry.alg).parse::<KeyAlgorithm>()?),
+                    key_id: Some(entry.kid.clone()),
+                    ..Default::default()
+                },
+                algorithm: public_key_parameters(is_rsa, &public_pem)?,
+            };
+
+            Ok(SigningKey {
+                kid: entry.kid,
+                algorithm: entry.alg,
+                encoding_key,
+                decoding_key,
+                public_jwk: Some(public_jwk),
+            })
+        }
+        other => bail!("unsupported JWT algorithm {other:?}"),
+    }
+}
+
+/// Extracts the JWK parameters (`n`/`e` for RSA, `x` for Ed25519) from a
+/// PEM public key.
+fn public_key_parameters(is_rsa: bool, pem_bytes: &[u8]) -> Result<AlgorithmParameters> {
+    let pem = pem::parse(pem_bytes)?;
+    let key_bytes = match pem.tag() {
+        "PUBLIC KEY" => subject_public_key(pem.contents())?,
+        "RSA PUBLIC KEY" if is_rsa => pem.contents().to_vec(),
+        tag => bail!("unexpected PEM block {tag:?}"),
+    };
+
+    if !is_rsa {
+        ensure!(key_bytes.len() == 32, "Ed25519 public key must be 32 bytes");
+        return Ok(AlgorithmParameters::OctetKeyPair(OctetKeyPairParameters {
+            key_type: OctetKeyPairType::OctetKeyPair,
+            curve: EllipticCurve::Ed25519,
+            x: URL_SAFE_NO_PAD.encode(&key_bytes),
+        }));
+    }
+
+    // RSAPublicKey ::= SEQUENCE { modulus INTEGER, publicExponent INTEGER }
+    match simple_asn1::from_der(&key_bytes)?.as_slice() {
+        [ASN1Block::Sequence(_, fields)] => match fields.as_slice() {
+            [ASN1Block::Integer(_, n), ASN1Block::Integer(_, e)] => {
+                Ok(AlgorithmParameters::RSA(RSAKeyParameters {
+                    key_type: RSAKeyType::RSA,
+                    n: URL_SAFE_NO_PAD.encode(n.to_bytes_be().1),
+                    e: URL_SAFE_NO_PAD.encode(e.to_bytes_be().1),
+                }))
+            }
+            _ => bail!("malformed RSA public key"),
+        },
+        _ => bail!("malformed RSA public key"),
+    }
+}
+
+/// Returns the `subjectPublicKey` bits of a SubjectPublicKeyInfo structure.
+fn subject_public_key(der: &[u8]) -> Result<Vec<u8>> {
+    match simple_asn1::from_der(der)?.as_slice() {
+        [ASN1Block::Sequence(_, fields)] => match fields.as_slice() {
+            [ASN1Block::Sequence(..), ASN1Block::BitString(_, _, bits)] => Ok(bits.clone()),
+            _ => bail!("malformed SubjectPublicKeyInfo"),
+        },
+        _ => bail!("malformed SubjectPublicKeyInfo"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Serialize, Deserialize, PartialEq)]
+    struct TestClaims {
+        sub: String,
+        exp: usize,
+    }
+
+    fn claims() -> TestClaims {
+        TestClaims {
+            sub: "user".to_string(),
+            // 2100-01-01, so every call gives the same claims
+            exp: 4_102_444_800,
+        }
+    }
+
+    /// Loads `testdata/keys.json` with `kid` as the active key.
+    fn keys_with_active(kid: &str) -> JwtKeys {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("src/auth/testdata/keys.json");
+        let mut keys = JwtKeys::from_file(&path).unwrap();
+        keys.active = keys.keys.iter().position(|key| key.kid == kid).unwrap();
+        keys
+    }
+
+    fn header_kid(token: &str) -> Option<String> {
+        decode_header(token).unwrap().kid
+    }
+
+    #[test]
+    fn rsa_key_signs_and_verifies() {
+        let keys = keys_with_active("rsa");
+
+        let token = keys.sign(&claims()).unwrap();
+
+        assert_eq!(header_kid(&token).as_deref(), Some("rsa"));
+        assert_eq!(decode_header(&token).unwrap().alg, Algorithm::RS256);
+        assert_eq!(keys.verify::<TestClaims>(&token).unwrap(), claims());
+    }
+
+    #[test]
+    fn ec_key_signs_and_verifies() {
+        let keys = keys_with_active("ed25519");
+
+        let token = keys.sign(&claims()).unwrap();
+
+        assert_eq!(header_kid(&token).as_deref(), Some("ed25519"));
+        assert_eq!(decode_header(&token).unwrap().alg, Algorithm::EdDSA);
+        assert_eq!(keys.verify::<TestClaims>(&token).unwrap(), claims());
+    }
+
+    #[test]
+    fn secret_key_signs_and_verifies() {
+        let keys = JwtKeys::from_secret("secret");
+
+        let token = keys.sign(&claims()).unwrap();
+
+        assert_eq!(header_kid(&token).as_deref(), Some(DEFAULT_KID));
+        assert_eq!(keys.verify::<TestClaims>(&token).unwrap(), claims());
+        assert!(JwtKeys::from_secret("other").verify::<TestClaims>(&token).is_err());
+    }
+
+    #[test]
+    fn tokens_from_a_retired_active_key_still_verify() {
+        let token = keys_with_active("rsa").sign(&claims()).unwrap();
+
+        assert!(keys_with_active("ed25519").verify::<TestClaims>(&token).is_ok());
+    }
+
+    #[test]
+    fn jwks_publishes_only_asymmetric_keys() {
+        let jwks = keys_with_active("rsa").jwks();
+
+        let kids: Vec<_> = jwks.keys.iter().map(|key| key.common.key_id.clone().unwrap()).collect();
+        assert_eq!(kids, ["rsa", "ed25519"]);
+        assert!(matches!(jwks.keys[0].algorithm, AlgorithmParameters::RSA(_)));
+        match &jwks.keys[1].algorithm {
+            AlgorithmParameters::OctetKeyPair(params) => {
+                assert_eq!(URL_SAFE_NO_PAD.decode(&params.x).unwrap().len(), 32)
+            }
+            other => panic!("unexpected parameters {other:?}"),
+        }
+    }
+
+    #[test]
+    fn unknown_kid_is_rejected() {
+        let mut header = Header::new(Algorithm::HS256);
+        header.kid = Some("missing".to_string());
+        let token = encode(&header, &claims(), &EncodingKey::from_secret(b"test-secret")).unwrap();
+
+        assert!(keys_with_active("rsa").verify::<TestClaims>(&token).is_err());
+    }
+
+    #[test]
+    fn wrong_algorithm_for_kid_is_rejected() {
+        // HS256 keyed with the RSA public key: verifying must not accept it
+        // just because the header names the RSA key.
+        let public_pem = include_bytes!("testdata/rsa.pub.pem");
+        let mut header = Header::new(Algorithm::HS256);
+        header.kid = Some("rsa".to_string());
+        let token = encode(&header, &claims(), &EncodingKey::from_secret(public_pem)).unwrap();
+
+        assert!(keys_with_active("rsa").verify::<TestClaims>(&token).is_err());
+    }
+}
@@ -1,22 +1,40 @@
 use anyhow::Result;
 use axum::{
-    extract::{Request, State},
+    extract::{MatchedPath, Request, State},
     http::{header::AUTHORIZATION, StatusCode},
     middleware::Next,
     response::Response,
 };
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
 use chrono::{Duration, Utc};
-use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
+use rand::RngCore;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use uuid::Uuid;
 
 use crate::AppState;
 
-const JWT_SECRET: &str = "your-secret-key"; // In production, use environment variable
+pub mod api_keys;
+mod client;
+pub mod email_tokens;
+mod keys;
+pub mod oidc;
+pub mod sessions;
+pub mod throttle;
+pub mod totp;
+
+pub use client::ClientInfo;
+pub use keys::JwtKeys;
+
+/// Session the current request's access token belongs to, set by
+/// `auth_middleware` alongside the user ID.
+#[derive(Debug, Clone, Copy)]
+pub struct CurrentSession(pub Uuid);
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Claims {
     pub sub: String, // Subject (user ID)
+    pub sid: String, // Session ID
     pub exp: usize,  // Expiration time
     pub iat: usize,  // Issued at
 }
@@ -25,47 +43,106 @@ pub struct Claims {
 pub struct LoginRequest {
     pub email: String,
     pub password: String,
+    pub device_name: Option<String>,
+}
+
+/// Claims of the short-lived token handed out by the first login step when
+/// the account has 2FA enabled. It can only be exchanged for a full login.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChallengeClaims {
+    pub sub: String,     // Subject (user ID)
+    pub purpose: String, // Always `CHALLENGE_PURPOSE`
+    pub exp: usize,      // Expiration time
+    pub iat: usize,      // Issued at
+}
+
+const CHALLENGE_PURPOSE: &str = "2fa";
+
+/// How long the user has to enter their second factor.
+pub const CHALLENGE_TTL_SECS: i64 = 5 * 60;
+
+#[derive(Debug, Deserialize)]
+pub struct RefreshRequest {
+    pub refresh_token: String,
 }
 
 #[derive(Debug, Serialize)]
 pub struct LoginResponse {
     pub token: String,
+    pub refresh_token: String,
+    pub expires_in: i64,
     pub user_id: Uuid,
     pub name: String,
     pub email: String,
 }
 
-pub fn create_token(user_id: Uuid) -> Result<String> {
+#[derive(Debug, Serialize)]
+pub struct TwoFactorChallengeResponse {
+    pub two_factor_required: bool,
+    pub challenge_token: String,
+    pub expires_in: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TokenResponse {
+    pub token: String,
+    pub refresh_token: String,
+    pub expires_in: i64,
+}
+
+pub fn create_token(keys: &JwtKeys, user_id: Uuid, session_id: Uuid, ttl: Duration) -> Result<String> {
     let now = Utc::now();
-    let expire = now + Duration::hours(24);
+    let expire = now + ttl;
 
     let claims = Claims {
         sub: user_id.to_string(),
+        sid: session_id.to_string(),
+        exp: expire.timestamp() as usize,
+        iat: now.timestamp() as usize,
+    };
+
+    keys.sign(&claims)
+}
+
+pub fn verify_token(keys: &JwtKeys, token: &str) -> Result<Claims> {
+    keys.verify(token)
+}
+
+pub fn create_challenge_token(keys: &JwtKeys, user_id: Uuid) -> Result<String> {
+    let now = Utc::now();
+    let expire = now + Duration::seconds(CHALLENGE_TTL_SECS);
+
+    let claims = ChallengeClaims {
+        sub: user_id.to_string(),
+        purpose: CHALLENGE_PURPOSE.to_string(),
         exp: expire.timestamp() as usize,
         iat: now.timestamp() as usize,
     };
 
-    let token = encode(
-        &Header::default(),
-        &claims,
-        &EncodingKey::from_secret(JWT_SECRET.as_ref()),
-    )?;
+    keys.sign(&claims)
+}
 
-    Ok(token)
+/// Returns the user ID a 2FA challenge token was issued for.
+pub fn verify_challenge_token(keys: &JwtKeys, token: &str) -> Result<Uuid> {
+    let claims: ChallengeClaims = keys.verify(token)?;
+    anyhow::ensure!(claims.purpose == CHALLENGE_PURPOSE, "not a 2FA challenge token");
+    Ok(Uuid::parse_str(&claims.sub)?)
 }
 
-pub fn verify_token(token: &str) -> Result<Claims> {
-    let token_data = decode::<Claims>(
-        token,
-        &DecodingKey::from_secret(JWT_SECRET.as_ref()),
-        &Validation::default(),
-    )?;
+/// Generates a random URL-safe token with 256 bits of entropy.
+pub fn generate_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    URL_SAFE_NO_PAD.encode(bytes)
+}
 
-    Ok(token_data.claims)
+/// SHA-256 hex digest used to store tokens without keeping them in the clear.
+pub fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
 }
 
 pub async fn auth_middleware(
-    State(_state): State<AppState>,
+    State(state): State<AppState>,
     mut request: Request,
     next: Next,
 ) -> Result<Response, StatusCode> {
@@ -84,14 +161,45 @@ pub async fn auth_middleware(
     }
 
     let token = auth_header.trim_start_matches("Bearer ");
+
+    if token.starts_with(api_keys::API_KEY_PREFIX) {
+        let allowed = request
+            .extensions()
+            .get::<MatchedPath>()
+            .is_some_and(|path| api_keys::API_KEY_ROUTES.contains(&path.as_str()));
+        if !allowed {
+            return Err(StatusCode::FORBIDDEN);
+        }
+
+        let (bot_id, scope) = api_keys::authenticate_api_key(&state, token)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+            .ok_or(StatusCode::UNAUTHORIZED)?;
+
+        // Handlers check the scope against the chat and action themselves
+        request.extensions_mut().insert(bot_id);
+        request.extensions_mut().insert(scope);
+        return Ok(next.run(request).await);
+    }
     
-    match verify_token(token) {
+    match verify_token(&state.jwt_keys, token) {
         Ok(claims) => {
-            // Add user ID to request extensions
             let user_id = Uuid::parse_str(&claims.sub).map_err(|_| StatusCode::UNAUTHORIZED)?;
+            let session_id = Uuid::parse_str(&claims.sid).map_err(|_| StatusCode::UNAUTHORIZED)?;
+
+            // Tokens stay valid until they expire, so check the session wasn't revoked
+            let active = sessions::touch_session(&state, user_id, session_id)
+                .await
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+            if !active {
+                return Err(StatusCode::UNAUTHORIZED);
+            }
+
+            // Add user and session IDs to request extensions
             request.extensions_mut().insert(user_id);
+            request.extensions_mut().insert(CurrentSession(session_id));
             Ok(next.run(request).await)
         }
         Err(_) => Err(StatusCode::UNAUTHORIZED),
     }
-}
\ No newline at end of file
+}
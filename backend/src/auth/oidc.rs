@@ -0,0 +1,309 @@
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{Duration, Utc};
+use jsonwebtoken::{decode, decode_header, jwk::JwkSet, Algorithm, DecodingKey, Validation};
+use reqwest::Url;
+use serde::{Deserialize, Deserializer, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::sync::{OnceCell, RwLock};
+
+use super::{generate_token, hash_token};
+use crate::AppState;
+
+/// How long the user has to finish signing in at the provider.
+const LOGIN_STATE_TTL_MINUTES: i64 = 10;
+
+// Sign-in uses the authorization code flow with PKCE. `start_login` stores
+// the PKCE verifier and nonce under a random `state` and returns the
+// provider's authorization URL; the provider sends the browser back to the
+// frontend with `code` and `state`, which the frontend posts to us so
+// `finish_login` can redeem the code and verify the ID token.
+
+/// An OpenID Connect provider, configured from the environment. Provider
+/// metadata is discovered on first use and signing keys are refetched when
+/// a token names a key we haven't seen.
+pub struct OidcClient {
+    issuer: String,
+    client_id: String,
+    client_secret: Option<String>,
+    redirect_url: String,
+    scopes: String,
+    http: reqwest::Client,
+    metadata: OnceCell<ProviderMetadata>,
+    jwks: RwLock<JwkSet>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct OidcAuthorizeResponse {
+    pub authorization_url: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct OidcCallbackRequest {
+    pub code: String,
+    pub state: String,
+    pub device_name: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ProviderMetadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenEndpointResponse {
+    id_token: String,
+}
+
+/// The ID token claims cam-chat uses.
+#[derive(Debug, Deserialize)]
+pub struct IdTokenClaims {
+    pub sub: String,
+    pub email: Option<String>,
+    #[serde(default, deserialize_with = "bool_or_string")]
+    pub email_verified: bool,
+    pub name: Option<String>,
+    pub picture: Option<String>,
+    nonce: Option<String>,
+}
+
+/// A verified sign-in: the provider's issuer plus the ID token claims.
+#[derive(Debug)]
+pub struct OidcLogin {
+    pub issuer: String,
+    pub claims: IdTokenClaims,
+}
+
+impl OidcClient {
+    /// Reads `OIDC_ISSUER_URL`, `OIDC_CLIENT_ID` and the optional
+    /// `OIDC_CLIENT_SECRET`, `OIDC_REDIRECT_URL` and `OIDC_SCOPES`. Returns
+    /// `None` when no issuer is set, which disables SSO.
+    pub fn from_env(app_base_url: &str) -> Result<Option<Self>> {
+        let Ok(issuer) = std::env::var("OIDC_ISSUER_URL") else {
+            return Ok(None);
+        };
+        let client_id = std::env::var("OIDC_CLIENT_ID")
+            .map_err(|_| anyhow!("OIDC_ISSUER_URL requires OIDC_CLIENT_ID"))?;
+
+        Ok(Some(Self {
+            issuer: issuer.trim_end_matches('/').to_string(),
+            client_id,
+            client_secret: std::env::var("OIDC_CLIENT_SECRET").ok(),
+            redirect_url: std::env::var("OIDC_REDIRECT_URL")
+                .unwrap_or_else(|_| format!("{}/auth/oidc/callback", app_base_url)),
+            scopes: std::env::var("OIDC_SCOPES").unwrap_or_else(|_| "openid email profile".to_string()),
+            http: reqwest::Client::new(),
+            metadata: OnceCell::new(),
+            jwks: RwLock::new(JwkSet { keys: Vec::new() }),
+        }))
+    }
+
+    async fn metadata(&self) -> Result<&ProviderMetadata> {
+        self.metadata
+            .get_or_try_init(|| async {
+                let url = format!("{}/.well-known/openid-configuration", self.issuer);
+                let metadata: ProviderMetadata = self
+                    .http
+                    .get(&url)
+                    .send()
+                    .await?
+                    .error_for_status()?
+                    .json()
+                    .await
+                    .with_context(|| format!("fetching {}", url))?;
+
+                ensure!(
+                    metadata.issuer.trim_end_matches('/') == self.issuer,
+                    "provider reports issuer {:?}, expected {:?}",
+                    metadata.issuer,
+                    self.issuer
+                );
+                Ok(metadata)
+            })
+            .await
+    }
+
+    fn authorization_url(
+        &self,
+        metadata: &ProviderMetadata,
+        state: &str,
+        nonce: &str,
+        code_verifier: &str,
+    ) -> Result<String> {
+        let code_challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(code_verifier.as_bytes()));
+
+        let url = Url::parse_with_params(
+            &metadata.authorization_endpoint,
+            [
+                ("response_type", "code"),
+                ("client_id", self.client_id.as_str()),
+                ("redirect_uri", self.redirect_url.as_str()),
+                ("scope", self.scopes.as_str()),
+                ("state", state),
+                ("nonce", nonce),
+                ("code_challenge", code_challenge.as_str()),
+                ("code_challenge_method", "S256"),
+            ],
+        )?;
+
+        Ok(url.into())
+    }
+
+    /// Redeems an authorization code and returns the verified ID token claims.
+    async fn exchange_code(&self, code: &str, code_verifier: &str, nonce: &str) -> Result<OidcLogin> {
+        let metadata = self.metadata().await?;
+
+        let mut request = self.http.post(&metadata.token_endpoint);
+        let mut form = vec![
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", self.redirect_url.as_str()),
+            ("code_verifier", code_verifier),
+        ];
+        match &self.client_secret {
+            Some(secret) => request = request.basic_auth(&self.client_id, Some(secret)),
+            None => form.push(("client_id", self.client_id.as_str())),
+        }
+
+        let response = request.form(&form).send().await?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let body = response.text().await.unwrap_or_default();
+            bail!("token endpoint returned {}: {}", status, body);
+        }
+        let tokens: TokenEndpointResponse = response.json().await?;
+
+        let claims = self.verify_id_token(metadata, &tokens.id_token).await?;
+        ensure!(claims.nonce.as_deref() == Some(nonce), "ID token nonce mismatch");
+
+        Ok(OidcLogin {
+            issuer: metadata.issuer.clone(),
+            claims,
+        })
+    }
+
+    async fn verify_id_token(&self, metadata: &ProviderMetadata, id_token: &str) -> Result<IdTokenClaims> {
+        let header = decode_header(id_token)?;
+        ensure!(
+            !matches!(header.alg, Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512),
+            "ID tokens must be signed with the provider's public key"
+        );
+
+        let decoding_key = self.decoding_key(metadata, header.kid.as_deref()).await?;
+
+        let mut validation = Validation::new(header.alg);
+        validation.set_issuer(&[&metadata.issuer]);
+        validation.set_audience(&[&self.client_id]);
+        validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+
+        Ok(decode::<IdTokenClaims>(id_token, &decoding_key, &validation)?.claims)
+    }
+
+    /// Finds the provider key named by `kid`, refetching the key set once if
+    /// it isn't known yet (the provider may have rotated).
+    async fn decoding_key(&self, metadata: &ProviderMetadata, kid: Option<&str>) -> Result<DecodingKey> {
+        for refreshed in [false, true] {
+            if refreshed {
+                let jwks: JwkSet = self
+                    .http
+                    .get(&metadata.jwks_uri)
+                    .send()
+                    .await?
+                    .error_for_status()?
+                    .json()
+                    .await
+                    .with_context(|| format!("fetching {}", metadata.jwks_uri))?;
+                *self.jwks.write().await = jwks;
+            }
+
+            let jwks = self.jwks.read().await;
+            let jwk = match kid {
+                Some(kid) => jwks.find(kid),
+                None if jwks.keys.len() == 1 => jwks.keys.first(),
+                None => None,
+            };
+            if let Some(jwk) = jwk {
+                return Ok(DecodingKey::from_jwk(jwk)?);
+            }
+        }
+
+        bail!("no provider key matches ID token kid {:?}", kid)
+    }
+}
+
+/// Begins a sign-in and returns the URL to send the browser to.
+pub async fn start_login(state: &AppState, client: &OidcClient) -> Result<String> {
+    let metadata = client.metadata().await?;
+
+    let login_state = generate_token();
+    let nonce = generate_token();
+    let code_verifier = generate_token();
+
+    // Abandoned sign-ins are cleaned up here rather than by a job
+    sqlx::query!("DELETE FROM oidc_login_states WHERE expires_at < NOW()")
+        .execute(state.db.pool())
+        .await?;
+
+    sqlx::query!(
+        r#"
+        INSERT INTO oidc_login_states (state_hash, code_verifier, nonce, expires_at)
+        VALUES ($1, $2, $3, $4)
+        "#,
+        hash_token(&login_state),
+        code_verifier,
+        nonce,
+        Utc::now() + Duration::minutes(LOGIN_STATE_TTL_MINUTES)
+    )
+    .execute(state.db.pool())
+    .await?;
+
+    client.authorization_url(metadata, &login_state, &nonce, &code_verifier)
+}
+
+/// Completes a sign-in started by `start_login`. Returns `Ok(None)` if the
+/// state is unknown, expired or already used; errors from the provider or
+/// an invalid ID token come back as `Err`.
+pub async fn finish_login(
+    state: &AppState,
+    client: &OidcClient,
+    code: &str,
+    login_state: &str,
+) -> Result<Option<OidcLogin>> {
+    let pending = sqlx::query!(
+        r#"
+        DELETE FROM oidc_login_states
+        WHERE state_hash = $1 AND expires_at > NOW()
+        RETURNING code_verifier, nonce
+        "#,
+        hash_token(login_state)
+    )
+    .fetch_optional(state.db.pool())
+    .await?;
+
+    let Some(pending) = pending else {
+        return Ok(None);
+    };
+
+    client
+        .exchange_code(code, &pending.code_verifier, &pending.nonce)
+        .await
+        .map(Some)
+}
+
+// Some providers send `email_verified` as the string "true"
+fn bool_or_string<'de, D: Deserializer<'de>>(deserializer: D) -> Result<bool, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum BoolOrString {
+        Bool(bool),
+        String(String),
+    }
+
+    Ok(match BoolOrString::deserialize(deserializer)? {
+        BoolOrString::Bool(value) => value,
+        BoolOrString::String(value) => value.eq_ignore_ascii_case("true"),
+    })
+}
@@ -0,0 +1,226 @@
+use anyhow::Result;
+use chrono::{Duration, Utc};
+use tracing::warn;
+use uuid::Uuid;
+
+use super::{create_token, generate_token, hash_token, ClientInfo, TokenResponse};
+use crate::AppState;
+
+/// `last_active_at` is only written when older than this, so authenticated
+/// requests don't each cost a write.
+const ACTIVITY_RESOLUTION_SECS: i64 = 60;
+
+// Refresh tokens have the form `<session id>.<secret>`. Only a hash of the
+// current secret is stored; every refresh replaces it and keeps the old hash
+// in `rotated_refresh_tokens`, so presenting an older secret for a live
+// session means the token was copied.
+
+/// Creates a session for a fresh login and returns its first token pair.
+pub async fn start_session(
+    state: &AppState,
+    user_id: Uuid,
+    device_name: Option<String>,
+    client: &ClientInfo,
+) -> Result<TokenResponse> {
+    let session_id = Uuid::new_v4();
+    let secret = generate_token();
+    let expires_at = Utc::now() + state.config.refresh_token_ttl;
+
+    sqlx::query!(
+        r#"
+        INSERT INTO sessions (id, user_id, refresh_token_hash, expires_at, device_name, user_agent, ip_address)
+        VALUES ($1, $2, $3, $4, $5, $6, $7)
+        "#,
+        session_id,
+        user_id,
+        hash_token(&secret),
+        expires_at,
+        device_name,
+        client.user_agent,
+        client.ip_address
+    )
+    .execute(state.db.pool())
+    .await?;
+
+    issue_tokens(state, user_id, session_id, &secret)
+}
+
+/// Exchanges a refresh token for a new token pair, rotating the stored
+/// secret. Returns `None` if the token is invalid, expired or revoked.
+///
+/// Replaying a refresh token that has already been rotated revokes the whole
+/// session, logging out both the legitimate client and whoever copied it.
+pub async fn refresh_session(
+    state: &AppState,
+    refresh_token: &str,
+    client: &ClientInfo,
+) -> Result<Option<TokenResponse>> {
+    let Some((session_id, secret)) = parse_refresh_token(refresh_token) else {
+        return Ok(None);
+    };
+
+    let new_secret = generate_token();
+    let expires_at = Utc::now() + state.config.refresh_token_ttl;
+
+    let user_id = sqlx::query_scalar!(
+        r#"
+        WITH refreshed AS (
+            UPDATE sessions
+            SET refresh_token_hash = $3, refreshed_at = NOW(), last_active_at = NOW(), expires_at = $4,
+                user_agent = COALESCE($5, user_agent), ip_address = COALESCE($6, ip_address)
+            WHERE id = $1 AND refresh_token_hash = $2 AND revoked_at IS NULL AND expires_at > NOW()
+            RETURNING id, user_id
+        ), rotated AS (
+            INSERT INTO rotated_refresh_tokens (session_id, token_hash)
+            SELECT id, $2 FROM refreshed
+        )
+        SELECT user_id AS "user_id!" FROM refreshed
+        "#,
+        session_id,
+        hash_token(secret),
+        hash_token(&new_secret),
+        expires_at,
+        client.user_agent,
+        client.ip_address
+    )
+    .fetch_optional(state.db.pool())
+    .await?;
+
+    if let Some(user_id) = user_id {
+        return issue_tokens(state, user_id, session_id, &new_secret).map(Some);
+    }
+
+    // A secret that is merely wrong could be a guess; only one the session
+    // has already rotated past proves the token was copied
+    let reused = sqlx::query!(
+        r#"
+        UPDATE sessions SET revoked_at = NOW()
+        WHERE id = $1 AND revoked_at IS NULL
+          AND EXISTS (SELECT 1 FROM rotated_refresh_tokens WHERE session_id = $1 AND token_hash = $2)
+        "#,
+        session_id,
+        hash_token(secret)
+    )
+    .execute(state.db.pool())
+    .await?
+    .rows_affected()
+        > 0;
+
+    if reused {
+        warn!("Refresh token reuse detected, revoked session {}", session_id);
+        notify_revoked(state, session_id);
+    }
+
+    Ok(None)
+}
+
+/// Revokes the session the refresh token belongs to. Returns `false` if the
+/// token is not the session's current one.
+pub async fn end_session(state: &AppState, refresh_token: &str) -> Result<bool> {
+    let Some((session_id, secret)) = parse_refresh_token(refresh_token) else {
+        return Ok(false);
+    };
+
+    let result = sqlx::query!(
+        r#"
+        UPDATE sessions SET revoked_at = NOW()
+        WHERE id = $1 AND refresh_token_hash = $2 AND revoked_at IS NULL
+        "#,
+        session_id,
+        hash_token(secret)
+    )
+    .execute(state.db.pool())
+    .await?;
+
+    let ended = result.rows_affected() > 0;
+    if ended {
+        notify_revoked(state, session_id);
+    }
+
+    Ok(ended)
+}
+
+/// Revokes one of the user's sessions, e.g. a lost device. Returns `false` if
+/// no such live session belongs to the user.
+pub async fn revoke_session(state: &AppState, user_id: Uuid, session_id: Uuid) -> Result<bool> {
+    let result = sqlx::query!(
+        "UPDATE sessions SET revoked_at = NOW() WHERE id = $1 AND user_id = $2 AND revoked_at IS NULL",
+        session_id,
+        user_id
+    )
+    .execute(state.db.pool())
+    .await?;
+
+    let revoked = result.rows_affected() > 0;
+    if revoked {
+        notify_revoked(state, session_id);
+    }
+
+    Ok(revoked)
+}
+
+/// Revokes every live session of the user, e.g. after a password reset.
+pub async fn revoke_all_sessions(state: &AppState, user_id: Uuid) -> Result<()> {
+    let session_ids = sqlx::query_scalar!(
+        "UPDATE sessions SET revoked_at = NOW() WHERE user_id = $1 AND revoked_at IS NULL RETURNING id",
+        user_id
+    )
+    .fetch_all(state.db.pool())
+    .await?;
+
+    for session_id in session_ids {
+        notify_revoked(state, session_id);
+    }
+
+    Ok(())
+}
+
+/// Checks that an access token's session hasn't been revoked or expired and
+/// records activity on it. Returns `false` for revoked, expired or unknown
+/// sessions.
+pub async fn touch_session(state: &AppState, user_id: Uuid, session_id: Uuid) -> Result<bool> {
+    let last_active_at = sqlx::query_scalar!(
+        r#"
+        SELECT last_active_at FROM sessions
+        WHERE id = $1 AND user_id = $2 AND revoked_at IS NULL AND expires_at > NOW()
+        "#,
+        session_id,
+        user_id
+    )
+    .fetch_optional(state.db.pool())
+    .await?;
+
+    let Some(last_active_at) = last_active_at else {
+        return Ok(false);
+    };
+
+    if Utc::now() - last_active_at > Duration::seconds(ACTIVITY_RESOLUTION_SECS) {
+        sqlx::query!("UPDATE sessions SET last_active_at = NOW() WHERE id = $1", session_id)
+            .execute(state.db.pool())
+            .await?;
+    }
+
+    Ok(true)
+}
+
+/// Tells open WebSockets on the session to disconnect.
+fn notify_revoked(state: &AppState, session_id: Uuid) {
+    // Err just means no socket is currently listening
+    let _ = state.session_revoked_tx.send(session_id);
+}
+
+fn issue_tokens(state: &AppState, user_id: Uuid, session_id: Uuid, secret: &str) -> Result<TokenResponse> {
+    let ttl = state.config.access_token_ttl;
+    let token = create_token(&state.jwt_keys, user_id, session_id, ttl)?;
+
+    Ok(TokenResponse {
+        token,
+        refresh_token: format!("{}.{}", session_id, secret),
+        expires_in: ttl.num_seconds(),
+    })
+}
+
+fn parse_refresh_token(refresh_token: &str) -> Option<(Uuid, &str)> {
+    let (session_id, secret) = refresh_token.split_once('.')?;
+    Some((Uuid::parse_str(session_id).ok()?, secret))
+}
@@ -0,0 +1,361 @@
+use anyhow::{anyhow, bail, Context, Result};
+use axum::{
+    http::{header::RETRY_AFTER, StatusCode},
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, Duration, Utc};
+use tracing::warn;
+
+use crate::{db::Database, models::login_throttle::LoginThrottleScope, AppState};
+
+/// Failures allowed before each further attempt has to wait.
+const FREE_ATTEMPTS: i32 = 3;
+/// Per-IP limits are this many times the per-account ones, since many users
+/// can share an address behind NAT.
+const IP_LIMIT_FACTOR: i32 = 5;
+/// A run of failures is forgotten after this long without another one.
+const FAILURE_WINDOW_MINUTES: i64 = 60;
+
+// Failed logins are counted per email and per client IP. After FREE_ATTEMPTS
+// failures the next attempt must wait 1s, then 2s, 4s, ... and once the count
+// reaches the configured threshold the key is locked out for the full
+// duration, which is recorded in `lockout_events`. A successful login clears
+// the account's count but not the IP's, so an attacker can't reset their
+// address by logging into an account of their own.
+
+/// Error type for the login handlers, which need to send `Retry-After` along
+/// with `429`.
+#[derive(Debug)]
+pub enum LoginError {
+    Status(StatusCode),
+    TooManyAttempts { retry_after: i64 },
+}
+
+impl From<StatusCode> for LoginError {
+    fn from(status: StatusCode) -> Self {
+        Self::Status(status)
+    }
+}
+
+impl IntoResponse for LoginError {
+    fn into_response(self) -> Response {
+        match self {
+            Self::Status(status) => status.into_response(),
+            Self::TooManyAttempts { retry_after } => (
+                StatusCode::TOO_MANY_REQUESTS,
+                [(RETRY_AFTER, retry_after.to_string())],
+            )
+                .into_response(),
+        }
+    }
+}
+
+/// Fails with `TooManyAttempts` while the email or IP is backing off or
+/// locked out.
+pub async fn ensure_allowed(state: &AppState, email: &str, ip_address: Option<&str>) -> Result<(), LoginError> {
+    let blocked_until = sqlx::query_scalar!(
+        r#"
+        SELECT MAX(blocked_until) FROM login_failures
+        WHERE ((scope = 'account' AND key = $1) OR (scope = 'ip' AND key = $2))
+          AND blocked_until > NOW()
+        "#,
+        email,
+        ip_address
+    )
+    .fetch_one(state.db.pool())
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    match blocked_until {
+        Some(until) => Err(LoginError::TooManyAttempts {
+            retry_after: seconds_until(until),
+        }),
+        None => Ok(()),
+    }
+}
+
+/// Counts a failed password or second-factor check against both the email
+/// and the client IP.
+pub async fn record_failure(state: &AppState, email: &str, ip_address: Option<&str>) -> Result<()> {
+    record_scope_failure(state, LoginThrottleScope::Account, email, email, ip_address, 1).await?;
+
+    if let Some(ip) = ip_address {
+        record_scope_failure(state, LoginThrottleScope::Ip, ip, email, ip_address, IP_LIMIT_FACTOR)
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// Forgets the account's failures after a successful login.
+pub async fn clear_failures(state: &AppState, email: &str) -> Result<()> {
+    sqlx::query!(
+        "DELETE FROM login_failures WHERE scope = 'account' AND key = $1",
+        email
+    )
+    .execute(state.db.pool())
+    .await?;
+
+    Ok(())
+}
+
+async fn record_scope_failure(
+    state: &AppState,
+    scope: LoginThrottleScope,
+    key: &str,
+    email: &str,
+    ip_address: Option<&str>,
+    limit_factor: i32,
+) -> Result<()> {
+    let failure_count = sqlx::query_scalar!(
+        r#"
+        INSERT INTO login_failures (scope, key, failure_count, last_failure_at)
+        VALUES ($1, $2, 1, NOW())
+        ON CONFLICT (scope, key) DO UPDATE SET
+            failure_count = CASE
+                WHEN login_failures.last_failure_at < NOW() - make_interval(mins => $3) THEN 1
+                ELSE login_failures.failure_count + 1
+            END,
+            last_failure_at = NOW()
+        RETURNING failure_count
+        "#,
+        scope as LoginThrottleScope,
+        key,
+        FAILURE_WINDOW_MINUTES as i32
+    )
+    .fetch_one(state.db.pool())
+    .await?;
+
+    let Some((delay, locked_out)) = block_for(
+        failure_count,
+        limit_factor,
+        state.config.login_lockout_threshold,
+        state.config.login_lockout_duration,
+    ) else {
+        return Ok(());
+    };
+    let blocked_until = Utc::now() + delay;
+
+    sqlx::query!(
+        "UPDATE login_failures SET blocked_until = $3 WHERE scope = $1 AND key = $2",
+        scope as LoginThrottleScope,
+        key,
+        blocked_until
+    )
+    .execute(state.db.pool())
+    .await?;
+
+    if locked_out {
+        warn!(
+            "Login locked out for {:?} {} until {} after {} failures",
+            scope, key, blocked_until, failure_count
+        );
+
+        sqlx::query!(
+            r#"
+            INSERT INTO lockout_events (scope, key, user_id, ip_address, failure_count, locked_until)
+            VALUES ($1, $2, (SELECT id FROM users WHERE email = $3), $4, $5, $6)
+            "#,
+            scope as LoginThrottleScope,
+            key,
+            email,
+            ip_address,
+            failure_count,
+            blocked_until
+        )
+        .execute(state.db.pool())
+        .await?;
+    }
+
+    Ok(())
+}
+
+/// Lists recent lockouts for administrators, per account and per IP, with
+/// the addresses each account was attacked from.
+#[derive(Debug, Clone)]
+pub struct LockoutReport {
+    pub hours: i64,
+}
+
+impl Default for LockoutReport {
+    fn default() -> Self {
+        Self { hours: 24 }
+    }
+}
+
+impl LockoutReport {
+    /// Parses `--hours N`, how far back to look.
+    pub fn from_args(args: impl IntoIterator<Item = String>) -> Result<Self> {
+        let mut report = Self::default();
+        let mut args = args.into_iter();
+
+        while let Some(flag) = args.next() {
+            let value = args.next().ok_or_else(|| anyhow!("{} needs a value", flag))?;
+
+            match flag.as_str() {
+                "--hours" => {
+                    report.hours = value
+                        .parse()
+                        .ok()
+                        .filter(|hours| *hours > 0)
+                        .with_context(|| format!("invalid {} {:?}", flag, value))?;
+                }
+                _ => bail!("unknown option {}", flag),
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Prints the lockouts, most frequent first.
+    pub async fn print(&self, db: &Database) -> Result<()> {
+        let rows = sqlx::query!(
+            r#"
+            SELECT scope AS "scope!: LoginThrottleScope", key,
+                   COUNT(*) AS "lockouts!",
+                   MAX(created_at) AS "last_locked_at!",
+                   MAX(locked_until) AS "locked_until!",
+                   COALESCE(ARRAY_AGG(DISTINCT ip_address) FILTER (WHERE ip_address IS NOT NULL), '{}')
+                       AS "ip_addresses!"
+            FROM lockout_events
+            WHERE created_at > NOW() - make_interval(hours => $1)
+            GROUP BY scope, key
+            ORDER BY COUNT(*) DESC, MAX(created_at) DESC
+            "#,
+            self.hours as i32
+        )
+        .fetch_all(db.pool())
+        .await?;
+
+        if rows.is_empty() {
+            println!("No lockouts in the last {} hours", self.hours);
+            return Ok(());
+        }
+
+        println!("Lockouts in the last {} hours:", self.hours);
+        for row in rows {
+            let scope = match row.scope {
+                LoginThrottleScope::Account => "account",
+                LoginThrottleScope::Ip => "ip",
+            };
+            let active = if row.locked_until > Utc::now() { " (still locked)" } else { "" };
+
+            println!(
+                "{:<8} {:<40} {} lockout(s), last at {}{}, from {}",
+                scope,
+                row.key,
+                row.lockouts,
+                row.last_locked_at.format("%Y-%m-%d %H:%M:%S UTC"),
+                active,
+                row.ip_addresses.join(", ")
+            );
+        }
+
+        Ok(())
+    }
+}
+
+/// How long a key has to wait after its `failure_count`th failure in a row,
+/// and whether that is a full lockout. `None` while attempts are still free.
+fn block_for(
+    failure_count: i32,
+    limit_factor: i32,
+    lockout_threshold: i32,
+    lockout_duration: Duration,
+) -> Option<(Duration, bool)> {
+    let free_attempts = FREE_ATTEMPTS * limit_factor;
+
+    if failure_count >= lockout_threshold * limit_factor {
+        Some((lockout_duration, true))
+    } else if failure_count >= free_attempts {
+        let backoff = Duration::seconds(1 << (failure_count - free_attempts).min(30));
+        Some((backoff.min(lockout_duration), false))
+    } else {
+        None
+    }
+}
+
+fn seconds_until(until: DateTime<Utc>) -> i64 {
+    let millis = (until - Utc::now()).num_milliseconds();
+    ((millis + 999) / 1000).max(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const THRESHOLD: i32 = 10;
+
+    fn lockout_duration() -> Duration {
+        Duration::minutes(15)
+    }
+
+    fn account_block(failure_count: i32) -> Option<(Duration, bool)> {
+        block_for(failure_count, 1, THRESHOLD, lockout_duration())
+    }
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn first_failures_are_free() {
+        for failure_count in 1..FREE_ATTEMPTS {
+            assert_eq!(account_block(failure_count), None);
+        }
+    }
+
+    #[test]
+    fn backoff_doubles_after_the_free_attempts() {
+        assert_eq!(account_block(FREE_ATTEMPTS), Some((Duration::seconds(1), false)));
+        assert_eq!(account_block(FREE_ATTEMPTS + 1), Some((Duration::seconds(2), false)));
+        assert_eq!(account_block(FREE_ATTEMPTS + 2), Some((Duration::seconds(4), false)));
+        assert_eq!(account_block(THRESHOLD - 1), Some((Duration::seconds(64), false)));
+    }
+
+    #[test]
+    fn threshold_locks_out_for_the_full_duration() {
+        assert_eq!(account_block(THRESHOLD), Some((lockout_duration(), true)));
+        assert_eq!(account_block(THRESHOLD + 20), Some((lockout_duration(), true)));
+    }
+
+    #[test]
+    fn backoff_never_exceeds_the_lockout() {
+        let block = block_for(40, 1, 100, lockout_duration());
+
+        assert_eq!(block, Some((lockout_duration(), false)));
+    }
+
+    #[test]
+    fn ip_limits_are_scaled() {
+        let ip_block = |failure_count| block_for(failure_count, IP_LIMIT_FACTOR, THRESHOLD, lockout_duration());
+
+        assert_eq!(ip_block(FREE_ATTEMPTS * IP_LIMIT_FACTOR - 1), None);
+        assert_eq!(ip_block(FREE_ATTEMPTS * IP_LIMIT_FACTOR), Some((Duration::seconds(1), false)));
+        assert_eq!(ip_block(THRESHOLD * IP_LIMIT_FACTOR - 1).map(|(_, locked)| locked), Some(false));
+        assert_eq!(ip_block(THRESHOLD * IP_LIMIT_FACTOR), Some((lockout_duration(), true)));
+    }
+
+    #[test]
+    fn lockout_report_defaults_to_a_day() {
+        assert_eq!(LockoutReport::from_args(args(&[])).unwrap().hours, 24);
+    }
+
+    #[test]
+    fn lockout_report_reads_hours() {
+        assert_eq!(LockoutReport::from_args(args(&["--hours", "6"])).unwrap().hours, 6);
+    }
+
+    #[test]
+    fn lockout_report_rejects_bad_hours() {
+        assert!(LockoutReport::from_args(args(&["--hours"])).is_err());
+        assert!(LockoutReport::from_args(args(&["--hours", "0"])).is_err());
+        assert!(LockoutReport::from_args(args(&["--hours", "-3"])).is_err());
+        assert!(LockoutReport::from_args(args(&["--hours", "soon"])).is_err());
+    }
+
+    #[test]
+    fn lockout_report_rejects_unknown_options() {
+        assert!(LockoutReport::from_args(args(&["--days", "2"])).is_err());
+    }
+}
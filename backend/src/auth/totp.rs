@@ -0,0 +1,156 @@
+use anyhow::Result;
+use rand::RngCore;
+use totp_rs::{Algorithm, Secret, TOTP};
+
+use super::hash_token;
+
+const DIGITS: usize = 6;
+const STEP_SECS: u64 = 30;
+/// Codes from one step either side of now are accepted to allow for clock drift.
+const SKEW_STEPS: u64 = 1;
+const SECRET_BYTES: usize = 20;
+const RECOVERY_CODE_COUNT: usize = 10;
+
+/// Generates a new base32-encoded TOTP secret.
+pub fn generate_secret() -> String {
+    let mut bytes = [0u8; SECRET_BYTES];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    Secret::Raw(bytes.to_vec()).to_encoded().to_string()
+}
+
+/// `otpauth://` URI for authenticator apps, usually rendered as a QR code.
+pub fn otpauth_uri(secret: &str, issuer: &str, account_name: &str) -> Result<String> {
+    Ok(build(secret, issuer, account_name)?.get_url())
+}
+
+/// Checks a code against the secret and returns the time step it belongs to.
+/// Steps at or before `last_used_step` are rejected so a code can't be replayed.
+pub fn verify_code(secret: &str, code: &str, last_used_step: Option<i64>) -> Result<Option<i64>> {
+    let totp = build(secret, "", "")?;
+    let current_step = chrono::Utc::now().timestamp() as u64 / STEP_SECS;
+    let first_unused_step = last_used_step.map_or(0, |last| last + 1);
+
+    let matched = (current_step.saturating_sub(SKEW_STEPS)..=current_step + SKEW_STEPS)
+        .filter(|&step| step as i64 >= first_unused_step)
+        .find(|&step| totp.generate(step * STEP_SECS) == code.trim());
+
+    Ok(matched.map(|step| step as i64))
+}
+
+/// Generates single-use recovery codes formatted as `xxxxx-xxxxx-xxxxx-xxxxx`.
+pub fn generate_recovery_codes() -> Vec<String> {
+    (0..RECOVERY_CODE_COUNT)
+        .map(|_| {
+            let mut bytes = [0u8; 10];
+            rand::thread_rng().fill_bytes(&mut bytes);
+            let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+            format!("{}-{}-{}-{}", &hex[0..5], &hex[5..10], &hex[10..15], &hex[15..20])
+        })
+        .collect()
+}
+
+/// Hash stored for a recovery code; dashes, spaces and case are ignored.
+pub fn hash_recovery_code(code: &str) -> String {
+    let normalized: String = code
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+    hash_token(&normalized)
+}
+
+fn build(secret: &str, issuer: &str, account_name: &str) -> Result<TOTP> {
+    let secret = Secret::Encoded(secret.to_string()).to_bytes()?;
+    let issuer = (!issuer.is_empty()).then(|| issuer.to_string());
+
+    Ok(TOTP::new(
+        Algorithm::SHA1,
+        DIGITS,
+        SKEW_STEPS as u8,
+        STEP_SECS,
+        secret,
+        issuer,
+        account_name.to_string(),
+    )?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn current_step() -> u64 {
+        chrono::Utc::now().timestamp() as u64 / STEP_SECS
+    }
+
+    fn code_at(secret: &str, step: u64) -> String {
+        build(secret, "", "").unwrap().generate(step * STEP_SECS)
+    }
+
+    #[test]
+    fn current_code_is_accepted_once() {
+        let secret = generate_secret();
+        let step = current_step();
+        let code = code_at(&secret, step);
+
+        let used = verify_code(&secret, &code, None).unwrap();
+
+        assert_eq!(used, Some(step as i64));
+        assert_eq!(verify_code(&secret, &code, used).unwrap(), None);
+    }
+
+    #[test]
+    fn earlier_step_is_rejected_after_a_later_one_was_used() {
+        let secret = generate_secret();
+        let step = current_step();
+
+        let previous = code_at(&secret, step - 1);
+
+        assert_eq!(verify_code(&secret, &previous, Some(step as i64)).unwrap(), None);
+    }
+
+    #[test]
+    fn next_step_is_accepted_for_clock_drift() {
+        let secret = generate_secret();
+        let step = current_step();
+
+        let next = code_at(&secret, step + 1);
+
+        assert_eq!(verify_code(&secret, &next, Some(step as i64)).unwrap(), Some(step as i64 + 1));
+    }
+
+    #[test]
+    fn code_outside_the_skew_window_is_rejected() {
+        let secret = generate_secret();
+
+        let stale = code_at(&secret, current_step() - 5);
+
+        assert_eq!(verify_code(&secret, &stale, None).unwrap(), None);
+    }
+
+    #[test]
+    fn recovery_codes_are_distinct_and_formatted() {
+        let codes = generate_recovery_codes();
+
+        assert_eq!(codes.len(), RECOVERY_CODE_COUNT);
+        for code in &codes {
+            let groups: Vec<&str> = code.split('-').collect();
+            assert_eq!(groups.len(), 4);
+            assert!(groups.iter().all(|group| group.len() == 5));
+        }
+        let mut unique = codes.clone();
+        unique.sort();
+        unique.dedup();
+        assert_eq!(unique.len(), codes.len());
+    }
+
+    #[test]
+    fn recovery_code_hash_ignores_dashes_spaces_and_case() {
+        let code = "abcde-12345-fghij-67890";
+
+        let hash = hash_recovery_code(code);
+
+        assert_eq!(hash_recovery_code("ABCDE 12345 FGHIJ 67890"), hash);
+        assert_eq!(hash_recovery_code("abcde1234 5fghij67890"), hash);
+        assert_ne!(hash_recovery_code("abcde-12345-fghij-67891"), hash);
+    }
+}
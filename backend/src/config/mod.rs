@@ -0,0 +1,62 @@
+use chrono::Duration;
+
+/// Runtime settings read from the environment at startup.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Lifetime of the JWT access tokens handed out at login and refresh.
+    pub access_token_ttl: Duration,
+    /// How long a session survives without being refreshed.
+    pub refresh_token_ttl: Duration,
+    /// Take the client IP from `X-Forwarded-For`. Only enable behind a proxy
+    /// that overwrites the header.
+    pub trust_proxy: bool,
+    /// Issuer shown next to the account in authenticator apps.
+    pub totp_issuer: String,
+    /// Refuse logins until the user has clicked the link in the
+    /// verification email.
+    pub require_email_verification: bool,
+    /// Frontend URL that links in emails point at.
+    pub app_base_url: String,
+    /// Failed logins for one email before it is locked out. Per-IP limits
+    /// are a multiple of this.
+    pub login_lockout_threshold: i32,
+    /// How long a lockout lasts.
+    pub login_lockout_duration: Duration,
+    /// How long after sending a message its sender can still edit it.
+    pub message_edit_window: Duration,
+    /// How long after a message was sent it can still be deleted for
+    /// everyone.
+    pub message_delete_window: Duration,
+}
+
+impl Config {
+    pub fn from_env() -> Self {
+        Self {
+            access_token_ttl: Duration::seconds(env_parse("ACCESS_TOKEN_TTL_SECS", 15 * 60)),
+            refresh_token_ttl: Duration::days(env_parse("REFRESH_TOKEN_TTL_DAYS", 30)),
+            trust_proxy: env_flag("TRUST_PROXY"),
+            totp_issuer: std::env::var("TOTP_ISSUER").unwrap_or_else(|_| "cam-chat".to_string()),
+            require_email_verification: env_flag("REQUIRE_EMAIL_VERIFICATION"),
+            app_base_url: std::env::var("APP_BASE_URL")
+                .map(|url| url.trim_end_matches('/').to_string())
+                .unwrap_or_else(|_| "http://localhost:3000".to_string()),
+            login_lockout_threshold: env_parse("LOGIN_LOCKOUT_THRESHOLD", 10),
+            login_lockout_duration: Duration::minutes(env_parse("LOGIN_LOCKOUT_MINUTES", 15)),
+            message_edit_window: Duration::minutes(env_parse("MESSAGE_EDIT_WINDOW_MINUTES", 15)),
+            message_delete_window: Duration::hours(env_parse("MESSAGE_DELETE_WINDOW_HOURS", 48)),
+        }
+    }
+}
+
+fn env_flag(name: &str) -> bool {
+    std::env::var(name)
+        .map(|value| matches!(value.trim().to_ascii_lowercase().as_str(), "1" | "true" | "yes" | "on"))
+        .unwrap_or(false)
+}
+
+fn env_parse<T: std::str::FromStr>(name: &str, default: T) -> T {
+    std::env::var(name)
+        .ok()
+        .and_then(|value| value.trim().parse().ok())
+        .unwrap_or(default)
+}
@@ -0,0 +1,70 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// What an API key is allowed to do within its chats.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ApiKeyPermission {
+    ReadMessages,
+    SendMessages,
+}
+
+impl ApiKeyPermission {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::ReadMessages => "read_messages",
+            Self::SendMessages => "send_messages",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "read_messages" => Some(Self::ReadMessages),
+            "send_messages" => Some(Self::SendMessages),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateBotRequest {
+    pub name: String,
+    pub avatar_url: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BotResponse {
+    pub id: Uuid,
+    pub name: String,
+    pub avatar_url: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateApiKeyRequest {
+    pub name: String,
+    pub chat_ids: Vec<Uuid>,
+    pub permissions: Vec<ApiKeyPermission>,
+    pub expires_in_days: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ApiKeyResponse {
+    pub id: Uuid,
+    pub name: String,
+    pub key_prefix: String,
+    pub chat_ids: Vec<Uuid>,
+    pub permissions: Vec<ApiKeyPermission>,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub last_used_at: Option<DateTime<Utc>>,
+}
+
+/// Returned once when a key is created; only its hash is kept.
+#[derive(Debug, Serialize)]
+pub struct CreatedApiKeyResponse {
+    pub api_key: String,
+    #[serde(flatten)]
+    pub key: ApiKeyResponse,
+}
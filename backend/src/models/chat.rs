@@ -7,12 +7,25 @@ use uuid::Uuid;
 pub struct Chat {
     pub id: Uuid,
     pub name: Option<String>,
+    pub description: Option<String>,
+    pub avatar_url: Option<String>,
     pub is_group: bool,
     pub created_by: Uuid,
+    #[sqlx(flatten)]
+    pub permissions: GroupPermissions,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
 }
 
+/// What ordinary members of a group may do. Admins can always do everything,
+/// and only they can change these.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, FromRow)]
+pub struct GroupPermissions {
+    pub only_admins_send_messages: bool,
+    pub only_admins_edit_info: bool,
+    pub only_admins_add_members: bool,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
 pub struct ChatParticipant {
     pub chat_id: Uuid,
@@ -21,19 +34,52 @@ pub struct ChatParticipant {
     pub is_admin: bool,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize)]
 pub struct ChatResponse {
     pub id: Uuid,
     pub name: Option<String>,
+    pub description: Option<String>,
+    pub avatar_url: Option<String>,
     pub is_group: bool,
+    /// `None` for direct chats.
+    pub permissions: Option<GroupPermissions>,
     pub participants: Vec<ChatParticipantResponse>,
     pub last_message: Option<LastMessageResponse>,
     pub unread_count: i64,
+    /// The requesting user's own settings. Left out of events sent to
+    /// several users.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub state: Option<ChatUserState>,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
 }
 
-#[derive(Debug, Serialize)]
+/// A participant's own settings for a chat, which nobody else sees.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ChatUserState {
+    pub is_archived: bool,
+    pub is_pinned: bool,
+    pub muted_until: Option<DateTime<Utc>>,
+    pub marked_unread: bool,
+    pub last_read_message_id: Option<Uuid>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MarkReadRequest {
+    /// Defaults to the chat's latest message.
+    pub message_id: Option<Uuid>,
+}
+
+/// How far a user has read in a chat.
+#[derive(Debug, Clone, Serialize)]
+pub struct ReadCursorResponse {
+    pub chat_id: Uuid,
+    pub last_read_message_id: Option<Uuid>,
+    pub last_read_at: Option<DateTime<Utc>>,
+    pub unread_count: i64,
+}
+
+#[derive(Debug, Clone, Serialize)]
 pub struct ChatParticipantResponse {
     pub user_id: Uuid,
     pub name: String,
@@ -42,11 +88,13 @@ pub struct ChatParticipantResponse {
     pub is_admin: bool,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize)]
 pub struct LastMessageResponse {
     pub content: String,
     pub sender_name: String,
     pub timestamp: DateTime<Utc>,
+    /// Deleted for everyone; `content` is empty.
+    pub is_deleted: bool,
 }
 
 #[derive(Debug, Deserialize)]
@@ -54,4 +102,113 @@ pub struct CreateChatRequest {
     pub name: Option<String>,
     pub is_group: bool,
     pub participant_ids: Vec<Uuid>,
-}
\ No newline at end of file
+}
+
+/// Changes to a group; fields left out stay as they are. An empty
+/// `description` or `avatar_url` removes it.
+#[derive(Debug, Deserialize)]
+pub struct UpdateChatRequest {
+    pub name: Option<String>,
+    pub description: Option<String>,
+    pub avatar_url: Option<String>,
+    pub permissions: Option<UpdateGroupPermissions>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdateGroupPermissions {
+    pub only_admins_send_messages: Option<bool>,
+    pub only_admins_edit_info: Option<bool>,
+    pub only_admins_add_members: Option<bool>,
+}
+
+/// Changes to the caller's own settings for a chat; fields left out stay as
+/// they are. `muted_until: null` unmutes.
+#[derive(Debug, Deserialize)]
+pub struct UpdateChatStateRequest {
+    pub archived: Option<bool>,
+    pub pinned: Option<bool>,
+    #[serde(default, deserialize_with = "present")]
+    pub muted_until: Option<Option<DateTime<Utc>>>,
+    pub marked_unread: Option<bool>,
+}
+
+/// Tells a field set to `null` (`Some(None)`) apart from a missing one
+/// (`None`, via `#[serde(default)]`).
+fn present<'de, D, T>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Some)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReorderPinnedChatsRequest {
+    pub chat_ids: Vec<Uuid>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChatListQuery {
+    /// Archived chats are only listed when this is `true`.
+    pub archived: Option<bool>,
+    pub pinned: Option<bool>,
+    pub muted: Option<bool>,
+    /// Chats with unread messages or marked unread.
+    pub unread: Option<bool>,
+    /// Matches group names and participant names.
+    pub q: Option<String>,
+    /// `next_cursor` of the previous page.
+    pub cursor: Option<String>,
+    pub limit: Option<u32>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AddParticipantsRequest {
+    pub user_ids: Vec<Uuid>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MembershipAction {
+    Added,
+    Removed,
+    Left,
+    Promoted,
+    Demoted,
+}
+
+/// A change to one member of a group, made by `actor_id`.
+#[derive(Debug, Clone, Serialize)]
+pub struct MembershipChange {
+    pub chat_id: Uuid,
+    pub user_id: Uuid,
+    pub action: MembershipAction,
+    pub actor_id: Uuid,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_field_is_left_alone() {
+        let request: UpdateChatStateRequest = serde_json::from_str(r#"{"archived": true}"#).unwrap();
+        assert_eq!(request.muted_until, None);
+    }
+
+    #[test]
+    fn null_field_is_cleared() {
+        let request: UpdateChatStateRequest = serde_json::from_str(r#"{"muted_until": null}"#).unwrap();
+        assert_eq!(request.muted_until, Some(None));
+    }
+
+    #[test]
+    fn set_field_is_changed() {
+        let request: UpdateChatStateRequest =
+            serde_json::from_str(r#"{"muted_until": "2030-01-01T00:00:00Z"}"#).unwrap();
+        assert_eq!(
+            request.muted_until,
+            Some(Some("2030-01-01T00:00:00Z".parse::<DateTime<Utc>>().unwrap()))
+        );
+    }
+}
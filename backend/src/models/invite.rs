@@ -0,0 +1,44 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+#[derive(Debug, Deserialize)]
+pub struct CreateInviteRequest {
+    pub expires_in_hours: Option<i64>,
+    pub max_uses: Option<i32>,
+    #[serde(default)]
+    pub requires_approval: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct InviteResponse {
+    pub id: Uuid,
+    pub chat_id: Uuid,
+    pub code_prefix: String,
+    pub requires_approval: bool,
+    pub max_uses: Option<i32>,
+    pub use_count: i32,
+    pub created_by: Uuid,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+/// Returned once when an invite is created; only its hash is kept.
+#[derive(Debug, Serialize)]
+pub struct CreatedInviteResponse {
+    pub code: String,
+    pub url: String,
+    #[serde(flatten)]
+    pub invite: InviteResponse,
+}
+
+/// Someone waiting for an admin to let them into a group.
+#[derive(Debug, Clone, Serialize)]
+pub struct JoinRequestResponse {
+    pub id: Uuid,
+    pub chat_id: Uuid,
+    pub user_id: Uuid,
+    pub name: String,
+    pub avatar_url: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
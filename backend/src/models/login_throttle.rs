@@ -0,0 +1,11 @@
+use serde::{Deserialize, Serialize};
+
+/// What a run of failed logins is counted against.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[sqlx(type_name = "login_throttle_scope", rename_all = "snake_case")]
+pub enum LoginThrottleScope {
+    /// Keyed by normalized email, whether or not the account exists.
+    Account,
+    /// Keyed by client IP address.
+    Ip,
+}
@@ -13,6 +13,9 @@ pub struct Message {
     pub reply_to: Option<Uuid>,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
+    pub edited_at: Option<DateTime<Utc>>,
+    pub deleted_at: Option<DateTime<Utc>>,
+    pub deleted_by: Option<Uuid>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, sqlx::Type)]
@@ -23,9 +26,11 @@ pub enum MessageType {
     File,
     Audio,
     Video,
+    /// Written by the server to record an event, e.g. a member joining.
+    System,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MessageResponse {
     pub id: Uuid,
     pub chat_id: Uuid,
@@ -33,14 +38,64 @@ pub struct MessageResponse {
     pub content: String,
     pub message_type: MessageType,
     pub reply_to: Option<Uuid>,
+    /// What the reply shows of the message `reply_to` points at.
+    pub reply_preview: Option<ReplyPreview>,
     pub created_at: DateTime<Utc>,
+    /// When the sender last edited the message; `None` if never.
+    pub edited_at: Option<DateTime<Utc>>,
+    /// Set once the message is deleted for everyone; `content` is then empty.
+    pub deleted_at: Option<DateTime<Utc>>,
+    /// Each emoji the message was reacted with and how many people used it,
+    /// in the order they were first used.
+    pub reactions: Vec<ReactionCount>,
+    /// The emoji the requesting user reacted with. Left out of events sent to
+    /// the whole chat.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub my_reactions: Option<Vec<String>>,
 }
 
-#[derive(Debug, Serialize)]
+impl MessageResponse {
+    /// Drops what only the requesting user should see, for events that go
+    /// to everyone in the chat.
+    pub fn for_everyone(self) -> Self {
+        Self {
+            my_reactions: None,
+            ..self
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReactionCount {
+    pub emoji: String,
+    pub count: i64,
+}
+
+/// Someone adding or removing a reaction, sent to the chat's sockets.
+#[derive(Debug, Clone, Serialize)]
+pub struct ReactionChange {
+    pub chat_id: Uuid,
+    pub message_id: Uuid,
+    pub user_id: Uuid,
+    pub emoji: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReplyPreview {
+    pub sender_name: String,
+    /// The start of the message's content.
+    pub snippet: String,
+    /// Deleted for everyone; `snippet` is empty.
+    pub is_deleted: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MessageSenderResponse {
     pub id: Uuid,
     pub name: String,
     pub avatar_url: Option<String>,
+    /// Sent by a bot through an API key rather than by a person.
+    pub is_bot: bool,
 }
 
 #[derive(Debug, Deserialize)]
@@ -50,8 +105,66 @@ pub struct SendMessageRequest {
     pub reply_to: Option<Uuid>,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct EditMessageRequest {
+    pub content: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeleteMode {
+    /// Hide the message from the requesting user only.
+    #[default]
+    Me,
+    /// Replace the message with a tombstone for everyone in the chat.
+    Everyone,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeleteMessageQuery {
+    #[serde(default, rename = "for")]
+    pub mode: DeleteMode,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct GetMessagesQuery {
-    pub page: Option<u32>,
+    /// Messages older than this message.
+    pub before: Option<Uuid>,
+    /// Messages newer than this message.
+    pub after: Option<Uuid>,
+    /// This message with context on both sides, e.g. to jump to a reply.
+    pub around: Option<Uuid>,
     pub limit: Option<u32>,
-}
\ No newline at end of file
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SearchMessagesQuery {
+    /// Words to look for; quoted phrases, `or` and `-word` are understood.
+    pub q: String,
+    /// Only search this chat.
+    pub chat_id: Option<Uuid>,
+    pub sender_id: Option<Uuid>,
+    /// Messages sent at or after this time.
+    pub from: Option<DateTime<Utc>>,
+    /// Messages sent before this time.
+    pub to: Option<DateTime<Utc>>,
+    pub message_type: Option<MessageType>,
+    /// The last message of the previous page.
+    pub before: Option<Uuid>,
+    pub limit: Option<u32>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MessageSearchResult {
+    pub id: Uuid,
+    pub chat_id: Uuid,
+    pub sender: MessageSenderResponse,
+    pub content: String,
+    pub message_type: MessageType,
+    pub reply_to: Option<Uuid>,
+    pub created_at: DateTime<Utc>,
+    pub edited_at: Option<DateTime<Utc>>,
+    /// The parts of `content` that matched, HTML-escaped, with the matching
+    /// words wrapped in `<mark>` tags.
+    pub snippet: String,
+}
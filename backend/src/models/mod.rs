@@ -1,7 +1,16 @@
+// Row types mirror the schema even where handlers only use the response types.
+#![allow(dead_code)]
+
 pub mod user;
+pub mod bot;
 pub mod chat;
 pub mod message;
+pub mod email_token;
+pub mod invite;
+pub mod login_throttle;
+pub mod session;
 
 pub use user::User;
-pub use chat::Chat;
-pub use message::Message;
\ No newline at end of file
+pub use chat::*;
+pub use message::*;
+pub use session::Session;
@@ -14,6 +14,18 @@ pub struct User {
     pub last_seen: DateTime<Utc>,
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
+    pub totp_secret: Option<String>,
+    pub totp_enabled_at: Option<DateTime<Utc>>,
+    pub totp_last_used_step: Option<i64>,
+    pub email_verified_at: Option<DateTime<Utc>>,
+    pub is_bot: bool,
+    pub bot_owner_id: Option<Uuid>,
+}
+
+impl User {
+    pub fn has_two_factor(&self) -> bool {
+        self.totp_enabled_at.is_some()
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -45,4 +57,23 @@ impl From<User> for UserResponse {
             last_seen: user.last_seen,
         }
     }
-}
\ No newline at end of file
+}
+
+#[derive(Debug, Serialize)]
+pub struct TwoFactorEnrollmentResponse {
+    pub secret: String,
+    pub otpauth_uri: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TwoFactorCodeRequest {
+    /// A current TOTP code or an unused recovery code.
+    pub code: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TwoFactorLoginRequest {
+    pub challenge_token: String,
+    pub code: String,
+    pub device_name: Option<String>,
+}
@@ -0,0 +1,139 @@
+use axum::{extract::State, http::StatusCode, Json};
+use bcrypt::{hash, DEFAULT_COST};
+use serde_json::{json, Value};
+use tracing::error;
+
+use crate::{
+    auth::{email_tokens, sessions},
+    models::{
+        email_token::{EmailRequest, EmailTokenPurpose, ResetPasswordRequest, VerifyEmailRequest},
+        User,
+    },
+    routes::auth::{normalize_email, MIN_PASSWORD_LENGTH},
+    AppState,
+};
+
+/// Emails a password reset link. Always succeeds so the response doesn't
+/// reveal whether the address has an account.
+pub async fn forgot_password(
+    State(state): State<AppState>,
+    Json(payload): Json<EmailRequest>,
+) -> Result<Json<Value>, StatusCode> {
+    // The lookup and token are done off the request too, so the response
+    // takes as long whether or not the address has an account
+    tokio::spawn(async move {
+        if let Err(e) = send_password_reset(&state, &payload.email).await {
+            error!("Failed to send password reset email: {}", e);
+        }
+    });
+
+    Ok(Json(json!({
+        "success": true
+    })))
+}
+
+/// Sets a new password from a reset link and signs the account out
+/// everywhere.
+pub async fn reset_password(
+    State(state): State<AppState>,
+    Json(payload): Json<ResetPasswordRequest>,
+) -> Result<Json<Value>, StatusCode> {
+    if payload.password.len() < MIN_PASSWORD_LENGTH {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let user_id = email_tokens::consume_token(&state, &payload.token, EmailTokenPurpose::PasswordReset)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::BAD_REQUEST)?;
+
+    let password_hash =
+        hash(&payload.password, DEFAULT_COST).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    // Receiving the reset link proves the user owns the address too
+    sqlx::query!(
+        r#"
+        UPDATE users
+        SET password_hash = $1, email_verified_at = COALESCE(email_verified_at, NOW()), updated_at = NOW()
+        WHERE id = $2
+        "#,
+        password_hash,
+        user_id
+    )
+    .execute(state.db.pool())
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    sessions::revoke_all_sessions(&state, user_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(json!({
+        "success": true
+    })))
+}
+
+/// Sends a fresh verification link, e.g. when the first one expired. Like
+/// `forgot_password`, the response is the same for unknown addresses.
+pub async fn request_verification(
+    State(state): State<AppState>,
+    Json(payload): Json<EmailRequest>,
+) -> Result<Json<Value>, StatusCode> {
+    tokio::spawn(async move {
+        if let Err(e) = send_verification(&state, &payload.email).await {
+            error!("Failed to send verification email: {}", e);
+        }
+    });
+
+    Ok(Json(json!({
+        "success": true
+    })))
+}
+
+pub async fn verify_email(
+    State(state): State<AppState>,
+    Json(payload): Json<VerifyEmailRequest>,
+) -> Result<Json<Value>, StatusCode> {
+    let user_id =
+        email_tokens::consume_token(&state, &payload.token, EmailTokenPurpose::EmailVerification)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+            .ok_or(StatusCode::BAD_REQUEST)?;
+
+    sqlx::query!(
+        "UPDATE users SET email_verified_at = COALESCE(email_verified_at, NOW()), updated_at = NOW() WHERE id = $1",
+        user_id
+    )
+    .execute(state.db.pool())
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(json!({
+        "success": true
+    })))
+}
+
+async fn send_password_reset(state: &AppState, email: &str) -> anyhow::Result<()> {
+    if let Some(user) = find_user_by_email(state, email).await? {
+        email_tokens::send_password_reset_email(state, &user).await?;
+    }
+
+    Ok(())
+}
+
+async fn send_verification(state: &AppState, email: &str) -> anyhow::Result<()> {
+    if let Some(user) = find_user_by_email(state, email).await? {
+        if user.email_verified_at.is_none() {
+            email_tokens::send_verification_email(state, &user).await?;
+        }
+    }
+
+    Ok(())
+}
+
+async fn find_user_by_email(state: &AppState, email: &str) -> sqlx::Result<Option<User>> {
+    sqlx::query_as::<_, User>("SELECT * FROM users WHERE email = $1 AND NOT is_bot")
+        .bind(normalize_email(email))
+        .fetch_optional(state.db.pool())
+        .await
+}
@@ -1,30 +1,240 @@
 use axum::{extract::State, http::StatusCode, Json};
 use bcrypt::{hash, verify, DEFAULT_COST};
+use jsonwebtoken::jwk::JwkSet;
 use serde_json::{json, Value};
+use std::sync::OnceLock;
 use uuid::Uuid;
 
 use crate::{
-    auth::{create_token, LoginRequest, LoginResponse},
-    models::User,
+    auth::{
+        create_challenge_token, email_tokens, sessions,
+        throttle::{self, LoginError},
+        verify_challenge_token, ClientInfo, LoginRequest, LoginResponse, RefreshRequest,
+        TwoFactorChallengeResponse, CHALLENGE_TTL_SECS,
+    },
+    models::{
+        user::{CreateUserRequest, TwoFactorLoginRequest},
+        User,
+    },
+    routes::two_factor::{fetch_user, verify_second_factor},
     AppState,
 };
 
+pub(crate) const MIN_PASSWORD_LENGTH: usize = 8;
+
+/// Stored as the password hash of accounts without a password, i.e. bots and
+/// users created through SSO. It isn't valid bcrypt, so password logins fail
+/// until the user sets one with a password reset.
+pub(crate) const NO_PASSWORD_HASH: &str = "!";
+
+// Verified against when the email is unknown so that a missing account takes
+// as long to reject as a wrong password.
+static DUMMY_PASSWORD_HASH: OnceLock<String> = OnceLock::new();
+
+pub async fn register(
+    State(state): State<AppState>,
+    client: ClientInfo,
+    Json(payload): Json<CreateUserRequest>,
+) -> Result<Json<Value>, StatusCode> {
+    let email = normalize_email(&payload.email);
+    let name = payload.name.trim();
+
+    if !email.contains('@') || name.is_empty() || payload.password.len() < MIN_PASSWORD_LENGTH {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let password_hash =
+        hash(&payload.password, DEFAULT_COST).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let user = sqlx::query_as::<_, User>(
+        r#"
+        INSERT INTO users (id, email, name, avatar_url, password_hash, is_online, last_seen, created_at, updated_at)
+        VALUES ($1, $2, $3, $4, $5, false, NOW(), NOW(), NOW())
+        RETURNING *
+        "#
+    )
+    .bind(Uuid::new_v4())
+    .bind(&email)
+    .bind(name)
+    .bind(payload.avatar_url)
+    .bind(password_hash)
+    .fetch_one(state.db.pool())
+    .await
+    .map_err(|e| match e {
+        sqlx::Error::Database(db_err) if db_err.is_unique_violation() => StatusCode::CONFLICT,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    })?;
+
+    email_tokens::send_verification_email(&state, &user)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    if state.config.require_email_verification {
+        // No session until the link in the email has been followed
+        return Ok(Json(json!({
+            "success": true,
+            "data": {
+                "verification_required": true,
+                "user_id": user.id,
+                "email": user.email,
+            }
+        })));
+    }
+
+    login_response(&state, user, None, &client).await
+}
+
 pub async fn login(
     State(state): State<AppState>,
+    client: ClientInfo,
     Json(payload): Json<LoginRequest>,
+) -> Result<Json<Value>, LoginError> {
+    let email = normalize_email(&payload.email);
+    let ip_address = client.ip_address.as_deref();
+
+    throttle::ensure_allowed(&state, &email, ip_address).await?;
+
+    let Some(user) = authenticate(&state, &email, &payload.password).await? else {
+        throttle::record_failure(&state, &email, ip_address)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        return Err(StatusCode::UNAUTHORIZED.into());
+    };
+
+    if state.config.require_email_verification && user.email_verified_at.is_none() {
+        return Err(StatusCode::FORBIDDEN.into());
+    }
+
+    if user.has_two_factor() {
+        // The password checked out; hold back the session until the second factor does too
+        return Ok(two_factor_challenge(&state, &user)?);
+    }
+
+    throttle::clear_failures(&state, &email)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(login_response(&state, user, payload.device_name, &client).await?)
+}
+
+/// Second login step for accounts with 2FA: exchanges the challenge token
+/// from `login` plus a TOTP or recovery code for a full session.
+pub async fn login_two_factor(
+    State(state): State<AppState>,
+    client: ClientInfo,
+    Json(payload): Json<TwoFactorLoginRequest>,
+) -> Result<Json<Value>, LoginError> {
+    let user_id = verify_challenge_token(&state.jwt_keys, &payload.challenge_token)
+        .map_err(|_| StatusCode::UNAUTHORIZED)?;
+    let user = fetch_user(&state, user_id).await?;
+    let ip_address = client.ip_address.as_deref();
+
+    // Wrong codes count towards the same limits as wrong passwords
+    throttle::ensure_allowed(&state, &user.email, ip_address).await?;
+
+    let verified = verify_second_factor(&state, &user, &payload.code)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if !verified {
+        throttle::record_failure(&state, &user.email, ip_address)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        return Err(StatusCode::UNAUTHORIZED.into());
+    }
+
+    throttle::clear_failures(&state, &user.email)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(login_response(&state, user, payload.device_name, &client).await?)
+}
+
+/// Looks up the user by email and checks the password against the stored
+/// bcrypt hash. Unknown emails and wrong passwords both yield `None`.
+async fn authenticate(state: &AppState, email: &str, password: &str) -> Result<Option<User>, StatusCode> {
+    let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE email = $1")
+        .bind(email)
+        .fetch_optional(state.db.pool())
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let Some(user) = user else {
+        let dummy_hash = DUMMY_PASSWORD_HASH
+            .get_or_init(|| hash(Uuid::new_v4().to_string(), DEFAULT_COST).unwrap_or_default());
+        let _ = verify(password, dummy_hash);
+        return Ok(None);
+    };
+
+    // Hashes that aren't valid bcrypt (e.g. seeded mock users) never match
+    match verify(password, &user.password_hash) {
+        Ok(true) => Ok(Some(user)),
+        _ => Ok(None),
+    }
+}
+
+pub async fn refresh(
+    State(state): State<AppState>,
+    client: ClientInfo,
+    Json(payload): Json<RefreshRequest>,
+) -> Result<Json<Value>, StatusCode> {
+    let tokens = sessions::refresh_session(&state, &payload.refresh_token, &client)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::UNAUTHORIZED)?;
+
+    Ok(Json(json!({
+        "success": true,
+        "data": tokens
+    })))
+}
+
+pub async fn logout(
+    State(state): State<AppState>,
+    Json(payload): Json<RefreshRequest>,
 ) -> Result<Json<Value>, StatusCode> {
-    // For demo purposes, we'll create mock users if they don't exist
-    let user = get_or_create_mock_user(&state, &payload.email, &payload.password)
+    let ended = sessions::end_session(&state, &payload.refresh_token)
         .await
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
-    // In a real app, you'd verify the password hash
-    // For now, we'll accept any password for demo purposes
-    
-    let token = create_token(user.id).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if !ended {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+
+    Ok(Json(json!({
+        "success": true
+    })))
+}
+
+/// Answers a first login step for an account with 2FA: a short-lived
+/// challenge token to exchange at `login_two_factor` instead of a session.
+pub(crate) fn two_factor_challenge(state: &AppState, user: &User) -> Result<Json<Value>, StatusCode> {
+    let challenge_token = create_challenge_token(&state.jwt_keys, user.id)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(json!({
+        "success": true,
+        "data": TwoFactorChallengeResponse {
+            two_factor_required: true,
+            challenge_token,
+            expires_in: CHALLENGE_TTL_SECS,
+        }
+    })))
+}
+
+pub(crate) async fn login_response(
+    state: &AppState,
+    user: User,
+    device_name: Option<String>,
+    client: &ClientInfo,
+) -> Result<Json<Value>, StatusCode> {
+    let tokens = sessions::start_session(state, user.id, device_name, client)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
     let response = LoginResponse {
-        token,
+        token: tokens.token,
+        refresh_token: tokens.refresh_token,
+        expires_in: tokens.expires_in,
         user_id: user.id,
         name: user.name,
         email: user.email,
@@ -36,59 +246,12 @@ pub async fn login(
     })))
 }
 
-async fn get_or_create_mock_user(
-    state: &AppState,
-    email: &str,
-    password: &str,
-) -> anyhow::Result<User> {
-    // Try to find existing user
-    let existing_user = sqlx::query_as::<_, User>(
-        "SELECT * FROM users WHERE email = $1"
-    )
-    .bind(email)
-    .fetch_optional(state.db.pool())
-    .await?;
-
-    if let Some(user) = existing_user {
-        return Ok(user);
-    }
-
-    // Create new mock user
-    let password_hash = hash(password, DEFAULT_COST)?;
-    let user_id = Uuid::new_v4();
-    let now = chrono::Utc::now();
-
-    // Extract name from email (simple approach for demo)
-    let name = email.split('@').next().unwrap_or("User").to_string();
-    
-    // Mock avatar URL based on email
-    let avatar_url = Some(format!(
-        "https://images.unsplash.com/photo-{}?w=150&h=150&fit=crop&crop=face",
-        match email {
-            e if e.contains("alice") => "1494790108755-2616b612b47c",
-            e if e.contains("bob") => "1507003211169-0a1dd7228f2d",
-            e if e.contains("sarah") => "1438761681033-6461ffad8d80",
-            _ => "1472099645785-5658abf4ff4e",
-        }
-    ));
-
-    let user = sqlx::query_as::<_, User>(
-        r#"
-        INSERT INTO users (id, email, name, avatar_url, password_hash, is_online, last_seen, created_at, updated_at)
-        VALUES ($1, $2, $3, $4, $5, true, $6, $7, $8)
-        RETURNING *
-        "#
-    )
-    .bind(user_id)
-    .bind(email)
-    .bind(name)
-    .bind(avatar_url)
-    .bind(password_hash)
-    .bind(now)
-    .bind(now)
-    .bind(now)
-    .fetch_one(state.db.pool())
-    .await?;
+/// Publishes the public halves of the signing keys so other services can
+/// verify cam-chat tokens.
+pub async fn jwks(State(state): State<AppState>) -> Json<JwkSet> {
+    Json(state.jwt_keys.jwks())
+}
 
-    Ok(user)
-}
\ No newline at end of file
+pub(crate) fn normalize_email(email: &str) -> String {
+    email.trim().to_lowercase()
+}
@@ -0,0 +1,298 @@
+use axum::{
+    extract::{Extension, Path, State},
+    http::StatusCode,
+    Json,
+};
+use chrono::{Duration, Utc};
+use serde_json::{json, Value};
+use uuid::Uuid;
+
+use crate::{
+    auth::{api_keys::generate_api_key, hash_token},
+    models::bot::{
+        ApiKeyPermission, ApiKeyResponse, BotResponse, CreateApiKeyRequest, CreateBotRequest,
+        CreatedApiKeyResponse,
+    },
+    routes::{
+        auth::NO_PASSWORD_HASH,
+        participants::{added_changes, insert_members, lock_group, record_changes, user_name},
+    },
+    AppState,
+};
+
+/// Bots still need a unique email; this domain can never receive mail.
+const BOT_EMAIL_DOMAIN: &str = "bots.cam-chat.invalid";
+
+pub async fn create_bot(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    Json(payload): Json<CreateBotRequest>,
+) -> Result<Json<Value>, StatusCode> {
+    let name = payload.name.trim();
+    if name.is_empty() {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let bot_id = Uuid::new_v4();
+    let bot = sqlx::query!(
+        r#"
+        INSERT INTO users (id, email, name, avatar_url, password_hash, is_online, last_seen, created_at, updated_at, is_bot, bot_owner_id)
+        VALUES ($1, $2, $3, $4, $5, false, NOW(), NOW(), NOW(), true, $6)
+        RETURNING id, name, avatar_url, created_at AS "created_at!"
+        "#,
+        bot_id,
+        format!("{}@{}", bot_id, BOT_EMAIL_DOMAIN),
+        name,
+        payload.avatar_url,
+        NO_PASSWORD_HASH,
+        user_id
+    )
+    .fetch_one(state.db.pool())
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(json!({
+        "success": true,
+        "data": BotResponse {
+            id: bot.id,
+            name: bot.name,
+            avatar_url: bot.avatar_url,
+            created_at: bot.created_at,
+        }
+    })))
+}
+
+pub async fn get_bots(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+) -> Result<Json<Value>, StatusCode> {
+    let bots = sqlx::query!(
+        r#"
+        SELECT id, name, avatar_url, created_at AS "created_at!"
+        FROM users
+        WHERE is_bot AND bot_owner_id = $1
+        ORDER BY created_at
+        "#,
+        user_id
+    )
+    .fetch_all(state.db.pool())
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let bots: Vec<BotResponse> = bots
+        .into_iter()
+        .map(|bot| BotResponse {
+            id: bot.id,
+            name: bot.name,
+            avatar_url: bot.avatar_url,
+            created_at: bot.created_at,
+        })
+        .collect();
+
+    Ok(Json(json!({
+        "success": true,
+        "data": bots
+    })))
+}
+
+/// Issues an API key for one of the caller's bots. The bot joins the key's
+/// chats, which must be groups the caller could add a member to.
+pub async fn create_api_key(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    Path(bot_id): Path<Uuid>,
+    Json(payload): Json<CreateApiKeyRequest>,
+) -> Result<Json<Value>, StatusCode> {
+    ensure_bot_owner(&state, user_id, bot_id).await?;
+
+    let name = payload.name.trim();
+    let mut chat_ids = payload.chat_ids;
+    chat_ids.sort();
+    chat_ids.dedup();
+    let mut permissions: Vec<ApiKeyPermission> = Vec::new();
+    for permission in payload.permissions {
+        if !permissions.contains(&permission) {
+            permissions.push(permission);
+        }
+    }
+
+    if name.is_empty() || chat_ids.is_empty() || permissions.is_empty() {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let (api_key, key_prefix) = generate_api_key();
+    let expires_at = payload
+        .expires_in_days
+        .filter(|days| *days > 0)
+        .map(|days| Utc::now() + Duration::days(days));
+    let permission_names: Vec<String> = permissions
+        .iter()
+        .map(|permission| permission.as_str().to_string())
+        .collect();
+
+    let mut tx = state
+        .db
+        .pool()
+        .begin()
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let key = sqlx::query!(
+        r#"
+        INSERT INTO api_keys (bot_id, name, key_prefix, key_hash, permissions, expires_at)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        RETURNING id, created_at
+        "#,
+        bot_id,
+        name,
+        key_prefix,
+        hash_token(&api_key),
+        &permission_names,
+        expires_at
+    )
+    .fetch_one(&mut *tx)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    sqlx::query!(
+        "INSERT INTO api_key_chats (api_key_id, chat_id) SELECT $1, UNNEST($2::uuid[])",
+        key.id,
+        &chat_ids
+    )
+    .execute(&mut *tx)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    // The bot joins as if the caller added it. Chats are locked in ID order
+    // so concurrent requests can't deadlock.
+    let actor_name = user_name(&mut tx, user_id).await?;
+    let bot_name = user_name(&mut tx, bot_id).await?;
+    let mut announcements = Vec::new();
+    for &chat_id in &chat_ids {
+        let group = lock_group(&mut tx, chat_id, user_id).await?;
+        if group.permissions.only_admins_add_members && !group.is_admin {
+            return Err(StatusCode::FORBIDDEN);
+        }
+
+        let added = insert_members(&mut tx, chat_id, &[bot_id]).await?;
+        let content = format!("{} added {}", actor_name, bot_name);
+        let announcement = record_changes(&mut tx, user_id, &content, added_changes(chat_id, user_id, &added))
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        announcements.extend(announcement);
+    }
+
+    tx.commit()
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    for announcement in announcements {
+        announcement.send(&state);
+    }
+
+    Ok(Json(json!({
+        "success": true,
+        "data": CreatedApiKeyResponse {
+            api_key,
+            key: ApiKeyResponse {
+                id: key.id,
+                name: name.to_string(),
+                key_prefix,
+                chat_ids,
+                permissions,
+                created_at: key.created_at,
+                expires_at,
+                last_used_at: None,
+            },
+        }
+    })))
+}
+
+pub async fn get_api_keys(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    Path(bot_id): Path<Uuid>,
+) -> Result<Json<Value>, StatusCode> {
+    ensure_bot_owner(&state, user_id, bot_id).await?;
+
+    let keys = sqlx::query!(
+        r#"
+        SELECT k.id, k.name, k.key_prefix, k.permissions, k.created_at, k.expires_at, k.last_used_at,
+               ARRAY(SELECT chat_id FROM api_key_chats WHERE api_key_id = k.id) AS "chat_ids!"
+        FROM api_keys k
+        WHERE k.bot_id = $1 AND k.revoked_at IS NULL
+        ORDER BY k.created_at
+        "#,
+        bot_id
+    )
+    .fetch_all(state.db.pool())
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let keys: Vec<ApiKeyResponse> = keys
+        .into_iter()
+        .map(|key| ApiKeyResponse {
+            id: key.id,
+            name: key.name,
+            key_prefix: key.key_prefix,
+            chat_ids: key.chat_ids,
+            permissions: key
+                .permissions
+                .iter()
+                .filter_map(|permission| ApiKeyPermission::parse(permission))
+                .collect(),
+            created_at: key.created_at,
+            expires_at: key.expires_at,
+            last_used_at: key.last_used_at,
+        })
+        .collect();
+
+    Ok(Json(json!({
+        "success": true,
+        "data": keys
+    })))
+}
+
+pub async fn revoke_api_key(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    Path((bot_id, key_id)): Path<(Uuid, Uuid)>,
+) -> Result<Json<Value>, StatusCode> {
+    ensure_bot_owner(&state, user_id, bot_id).await?;
+
+    let result = sqlx::query!(
+        "UPDATE api_keys SET revoked_at = NOW() WHERE id = $1 AND bot_id = $2 AND revoked_at IS NULL",
+        key_id,
+        bot_id
+    )
+    .execute(state.db.pool())
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    if result.rows_affected() == 0 {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    Ok(Json(json!({
+        "success": true
+    })))
+}
+
+/// `404` unless the bot exists and belongs to the user.
+async fn ensure_bot_owner(state: &AppState, user_id: Uuid, bot_id: Uuid) -> Result<(), StatusCode> {
+    let owned = sqlx::query_scalar!(
+        "SELECT EXISTS(SELECT 1 FROM users WHERE id = $1 AND is_bot AND bot_owner_id = $2)",
+        bot_id,
+        user_id
+    )
+    .fetch_one(state.db.pool())
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .unwrap_or(false);
+
+    if owned {
+        Ok(())
+    } else {
+        Err(StatusCode::NOT_FOUND)
+    }
+}
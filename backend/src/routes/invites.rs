@@ -0,0 +1,405 @@
+use axum::{
+    extract::{Extension, Path, State},
+    http::StatusCode,
+    Json,
+};
+use chrono::{Duration, Utc};
+use serde_json::{json, Value};
+use uuid::Uuid;
+
+use crate::{
+    auth::{generate_token, hash_token},
+    models::invite::{CreateInviteRequest, CreatedInviteResponse, InviteResponse, JoinRequestResponse},
+    routes::{
+        chats::fetch_chat,
+        participants::{added_changes, announce, chat_json, ensure_group_admin, insert_participants, lock_group, user_name},
+    },
+    ws::{self, ServerEvent},
+    AppState,
+};
+
+/// Characters of the code kept in the clear for display.
+const DISPLAYED_PREFIX_LEN: usize = 8;
+
+/// Creates an invite link for a group. Admins only.
+pub async fn create_invite(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    Path(chat_id): Path<Uuid>,
+    Json(payload): Json<CreateInviteRequest>,
+) -> Result<Json<Value>, StatusCode> {
+    if payload.max_uses.is_some_and(|max_uses| max_uses <= 0) {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    ensure_group_admin(&state, chat_id, user_id).await?;
+
+    let code = generate_token();
+    let code_prefix = code[..DISPLAYED_PREFIX_LEN].to_string();
+    let expires_at = payload
+        .expires_in_hours
+        .filter(|hours| *hours > 0)
+        .map(|hours| Utc::now() + Duration::hours(hours));
+
+    let invite = sqlx::query!(
+        r#"
+        INSERT INTO chat_invites (chat_id, created_by, code_prefix, code_hash, requires_approval, max_uses, expires_at)
+        VALUES ($1, $2, $3, $4, $5, $6, $7)
+        RETURNING id, created_at
+        "#,
+        chat_id,
+        user_id,
+        code_prefix,
+        hash_token(&code),
+        payload.requires_approval,
+        payload.max_uses,
+        expires_at
+    )
+    .fetch_one(state.db.pool())
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let url = format!("{}/join/{}", state.config.app_base_url, code);
+
+    Ok(Json(json!({
+        "success": true,
+        "data": CreatedInviteResponse {
+            code,
+            url,
+            invite: InviteResponse {
+                id: invite.id,
+                chat_id,
+                code_prefix,
+                requires_approval: payload.requires_approval,
+                max_uses: payload.max_uses,
+                use_count: 0,
+                created_by: user_id,
+                created_at: invite.created_at,
+                expires_at,
+            },
+        }
+    })))
+}
+
+/// Lists a group's invite links that haven't been revoked, including expired
+/// and used up ones. Admins only.
+pub async fn get_invites(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    Path(chat_id): Path<Uuid>,
+) -> Result<Json<Value>, StatusCode> {
+    ensure_group_admin(&state, chat_id, user_id).await?;
+
+    let invites = sqlx::query_as!(
+        InviteResponse,
+        r#"
+        SELECT id, chat_id, code_prefix, requires_approval, max_uses, use_count, created_by, created_at, expires_at
+        FROM chat_invites
+        WHERE chat_id = $1 AND revoked_at IS NULL
+        ORDER BY created_at
+        "#,
+        chat_id
+    )
+    .fetch_all(state.db.pool())
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(json!({
+        "success": true,
+        "data": invites
+    })))
+}
+
+/// Revokes an invite link. Pending requests made through it stay pending.
+/// Admins only.
+pub async fn revoke_invite(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    Path((chat_id, invite_id)): Path<(Uuid, Uuid)>,
+) -> Result<Json<Value>, StatusCode> {
+    ensure_group_admin(&state, chat_id, user_id).await?;
+
+    let result = sqlx::query!(
+        "UPDATE chat_invites SET revoked_at = NOW() WHERE id = $1 AND chat_id = $2 AND revoked_at IS NULL",
+        invite_id,
+        chat_id
+    )
+    .execute(state.db.pool())
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    if result.rows_affected() == 0 {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    Ok(Json(json!({
+        "success": true
+    })))
+}
+
+/// Joins a group through an invite link, or asks to if the link requires
+/// approval. Members following a link again don't use it up.
+pub async fn join_with_invite(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    Path(code): Path<String>,
+) -> Result<Json<Value>, StatusCode> {
+    let code_hash = hash_token(&code);
+
+    let chat_id = sqlx::query_scalar!(
+        "SELECT chat_id FROM chat_invites WHERE code_hash = $1",
+        code_hash
+    )
+    .fetch_optional(state.db.pool())
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .ok_or(StatusCode::NOT_FOUND)?;
+
+    let mut tx = state
+        .db
+        .pool()
+        .begin()
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    // Same lock as membership changes, taken before the invite's so the two
+    // are always locked in the same order
+    sqlx::query!("SELECT id FROM chats WHERE id = $1 FOR UPDATE", chat_id)
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let is_participant = sqlx::query_scalar!(
+        "SELECT EXISTS(SELECT 1 FROM chat_participants WHERE chat_id = $1 AND user_id = $2)",
+        chat_id,
+        user_id
+    )
+    .fetch_one(&mut *tx)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .unwrap_or(false);
+
+    if is_participant {
+        return joined_json(&state, chat_id, user_id).await;
+    }
+
+    let pending = fetch_pending_request(&mut tx, chat_id, user_id).await?;
+    if let Some(request) = pending {
+        return Ok(pending_json(request));
+    }
+
+    let invite = sqlx::query!(
+        r#"
+        UPDATE chat_invites SET use_count = use_count + 1
+        WHERE code_hash = $1 AND revoked_at IS NULL
+          AND (expires_at IS NULL OR expires_at > NOW())
+          AND (max_uses IS NULL OR use_count < max_uses)
+        RETURNING id, requires_approval
+        "#,
+        code_hash
+    )
+    .fetch_optional(&mut *tx)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .ok_or(StatusCode::NOT_FOUND)?;
+
+    if invite.requires_approval {
+        sqlx::query!(
+            "INSERT INTO chat_join_requests (chat_id, user_id, invite_id) VALUES ($1, $2, $3)",
+            chat_id,
+            user_id,
+            invite.id
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+        let request = fetch_pending_request(&mut tx, chat_id, user_id)
+            .await?
+            .ok_or(StatusCode::INTERNAL_SERVER_ERROR)?;
+
+        let admin_ids = sqlx::query_scalar!(
+            "SELECT user_id FROM chat_participants WHERE chat_id = $1 AND is_admin",
+            chat_id
+        )
+        .fetch_all(&mut *tx)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+        tx.commit()
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+        ws::notify_users(&state, admin_ids, ServerEvent::JoinRequested(request.clone()));
+
+        return Ok(pending_json(request));
+    }
+
+    let added = insert_participants(&mut tx, chat_id, &[user_id]).await?;
+    let name = user_name(&mut tx, user_id).await?;
+
+    announce(
+        &state,
+        tx,
+        user_id,
+        &format!("{} joined using an invite link", name),
+        added_changes(chat_id, user_id, &added),
+    )
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    joined_json(&state, chat_id, user_id).await
+}
+
+/// Lists the people waiting to join a group. Admins only.
+pub async fn get_join_requests(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    Path(chat_id): Path<Uuid>,
+) -> Result<Json<Value>, StatusCode> {
+    ensure_group_admin(&state, chat_id, user_id).await?;
+
+    let requests = sqlx::query_as!(
+        JoinRequestResponse,
+        r#"
+        SELECT r.id, r.chat_id, r.user_id, u.name, u.avatar_url, r.created_at
+        FROM chat_join_requests r
+        JOIN users u ON u.id = r.user_id
+        WHERE r.chat_id = $1 AND r.status = 'pending'
+        ORDER BY r.created_at
+        "#,
+        chat_id
+    )
+    .fetch_all(state.db.pool())
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(json!({
+        "success": true,
+        "data": requests
+    })))
+}
+
+/// Lets someone who asked to join into the group. Admins only.
+pub async fn approve_join_request(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    Path((chat_id, request_id)): Path<(Uuid, Uuid)>,
+) -> Result<Json<Value>, StatusCode> {
+    let mut tx = state
+        .db
+        .pool()
+        .begin()
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    if !lock_group(&mut tx, chat_id, user_id).await?.is_admin {
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    let requester_id = sqlx::query_scalar!(
+        r#"
+        UPDATE chat_join_requests SET status = 'approved', decided_by = $3, decided_at = NOW()
+        WHERE id = $1 AND chat_id = $2 AND status = 'pending'
+        RETURNING user_id
+        "#,
+        request_id,
+        chat_id,
+        user_id
+    )
+    .fetch_optional(&mut *tx)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .ok_or(StatusCode::NOT_FOUND)?;
+
+    let added = insert_participants(&mut tx, chat_id, &[requester_id]).await?;
+    let actor_name = user_name(&mut tx, user_id).await?;
+    let requester_name = user_name(&mut tx, requester_id).await?;
+
+    announce(
+        &state,
+        tx,
+        user_id,
+        &format!("{} approved {}'s request to join", actor_name, requester_name),
+        added_changes(chat_id, user_id, &added),
+    )
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    chat_json(&state, chat_id, user_id).await
+}
+
+/// Turns down a request to join. Admins only.
+pub async fn decline_join_request(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    Path((chat_id, request_id)): Path<(Uuid, Uuid)>,
+) -> Result<Json<Value>, StatusCode> {
+    ensure_group_admin(&state, chat_id, user_id).await?;
+
+    let result = sqlx::query!(
+        r#"
+        UPDATE chat_join_requests SET status = 'declined', decided_by = $3, decided_at = NOW()
+        WHERE id = $1 AND chat_id = $2 AND status = 'pending'
+        "#,
+        request_id,
+        chat_id,
+        user_id
+    )
+    .execute(state.db.pool())
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    if result.rows_affected() == 0 {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    Ok(Json(json!({
+        "success": true
+    })))
+}
+
+async fn fetch_pending_request(
+    conn: &mut sqlx::PgConnection,
+    chat_id: Uuid,
+    user_id: Uuid,
+) -> Result<Option<JoinRequestResponse>, StatusCode> {
+    sqlx::query_as!(
+        JoinRequestResponse,
+        r#"
+        SELECT r.id, r.chat_id, r.user_id, u.name, u.avatar_url, r.created_at
+        FROM chat_join_requests r
+        JOIN users u ON u.id = r.user_id
+        WHERE r.chat_id = $1 AND r.user_id = $2 AND r.status = 'pending'
+        "#,
+        chat_id,
+        user_id
+    )
+    .fetch_optional(&mut *conn)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+async fn joined_json(state: &AppState, chat_id: Uuid, user_id: Uuid) -> Result<Json<Value>, StatusCode> {
+    let chat = fetch_chat(state, chat_id, user_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(json!({
+        "success": true,
+        "data": {
+            "status": "joined",
+            "chat": chat
+        }
+    })))
+}
+
+fn pending_json(request: JoinRequestResponse) -> Json<Value> {
+    Json(json!({
+        "success": true,
+        "data": {
+            "status": "pending",
+            "request": request
+        }
+    }))
+}
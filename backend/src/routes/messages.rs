@@ -3,24 +3,48 @@ use axum::{
     http::StatusCode,
     Json,
 };
+use chrono::{DateTime, Utc};
 use serde_json::{json, Value};
+use sqlx::PgConnection;
+use std::collections::HashMap;
 use uuid::Uuid;
 
 use crate::{
-    models::{GetMessagesQuery, MessageResponse, MessageSenderResponse, SendMessageRequest, MessageType},
-    ws::ChatMessage,
+    auth::api_keys::ApiKeyScope,
+    models::{
+        bot::ApiKeyPermission, DeleteMessageQuery, DeleteMode, EditMessageRequest, GetMessagesQuery, MarkReadRequest,
+        MessageResponse, MessageSenderResponse, MessageType, ReactionCount, ReadCursorResponse, ReplyPreview,
+        SendMessageRequest,
+    },
+    ws::{self, ChatMessage, ServerEvent},
     AppState,
 };
 
+/// Messages returned per page when the request doesn't say.
+const DEFAULT_MESSAGE_PAGE_SIZE: i64 = 50;
+/// Most messages returned per page.
+const MAX_MESSAGE_PAGE_SIZE: i64 = 100;
+/// Characters of the replied-to message shown with a reply.
+const REPLY_SNIPPET_CHARS: i32 = 100;
+
+/// Lists a page of a chat's messages, newest first. Without a cursor it is
+/// the latest messages; `before` and `after` page from a message in either
+/// direction, and `around` returns a message with context on both sides.
 pub async fn get_messages(
     State(state): State<AppState>,
     Extension(user_id): Extension<Uuid>,
+    api_key: Option<Extension<ApiKeyScope>>,
     Path(chat_id): Path<Uuid>,
     Query(params): Query<GetMessagesQuery>,
 ) -> Result<Json<Value>, StatusCode> {
-    let page = params.page.unwrap_or(1);
-    let limit = params.limit.unwrap_or(50);
-    let offset = (page - 1) * limit;
+    if let Some(Extension(scope)) = &api_key {
+        scope.require(chat_id, ApiKeyPermission::ReadMessages)?;
+    }
+
+    let limit = params
+        .limit
+        .map_or(DEFAULT_MESSAGE_PAGE_SIZE, i64::from)
+        .clamp(1, MAX_MESSAGE_PAGE_SIZE);
 
     // Verify user is part of the chat
     let is_participant = sqlx::query_scalar!(
@@ -37,38 +61,98 @@ pub async fn get_messages(
         return Err(StatusCode::FORBIDDEN);
     }
 
-    let messages = sqlx::query!(
-        r#"
-        SELECT m.id, m.chat_id, m.sender_id, m.content, m.message_type as "message_type: MessageType", 
-               m.reply_to, m.created_at, u.name as sender_name, u.avatar_url as sender_avatar
-        FROM messages m
-        JOIN users u ON m.sender_id = u.id
-        WHERE m.chat_id = $1
-        ORDER BY m.created_at DESC
-        LIMIT $2 OFFSET $3
-        "#,
-        chat_id,
-        limit as i64,
-        offset as i64
-    )
-    .fetch_all(state.db.pool())
-    .await
-    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let mut conn = state
+        .db
+        .pool()
+        .acquire()
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let (rows, has_more_before, has_more_after) = match (params.before, params.after, params.around) {
+        (None, None, None) => {
+            let older = fetch_older(&mut conn, chat_id, user_id, None, false, limit + 1)
+                .await
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+            let (older, has_more_before) = take_page(older, limit);
+            (older, has_more_before, false)
+        }
+        (Some(before), None, None) => {
+            let anchor = message_position(&mut conn, chat_id, before).await?;
+            let older = fetch_older(&mut conn, chat_id, user_id, Some(anchor), false, limit + 1)
+                .await
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+            let newer = fetch_newer(&mut conn, chat_id, user_id, anchor, true, 1)
+                .await
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+            let (older, has_more_before) = take_page(older, limit);
+            (older, has_more_before, !newer.is_empty())
+        }
+        (None, Some(after), None) => {
+            let anchor = message_position(&mut conn, chat_id, after).await?;
+            let newer = fetch_newer(&mut conn, chat_id, user_id, anchor, false, limit + 1)
+                .await
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+            let older = fetch_older(&mut conn, chat_id, user_id, Some(anchor), true, 1)
+                .await
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+            let (mut newer, has_more_after) = take_page(newer, limit);
+            newer.reverse();
+            (newer, !older.is_empty(), has_more_after)
+        }
+        (None, None, Some(around)) => {
+            let anchor = message_position(&mut conn, chat_id, around).await?;
+            // The target message counts towards the newer half
+            let older_limit = (limit - 1) / 2;
+            let newer_limit = limit - older_limit;
+            let older = fetch_older(&mut conn, chat_id, user_id, Some(anchor), false, older_limit + 1)
+                .await
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+            let newer = fetch_newer(&mut conn, chat_id, user_id, anchor, true, newer_limit + 1)
+                .await
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+            let (older, has_more_before) = take_page(older, older_limit);
+            let (mut newer, has_more_after) = take_page(newer, newer_limit);
+            newer.reverse();
+            newer.extend(older);
+            (newer, has_more_before, has_more_after)
+        }
+        // Only one cursor at a time
+        _ => return Err(StatusCode::BAD_REQUEST),
+    };
 
-    let message_responses: Vec<MessageResponse> = messages
+    let message_ids: Vec<Uuid> = rows.iter().map(|m| m.id).collect();
+    let mut reactions = fetch_reactions(&mut conn, &message_ids, user_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let reply_ids: Vec<Uuid> = rows.iter().filter_map(|m| m.reply_to).collect();
+    let reply_previews = fetch_reply_previews(&mut conn, &reply_ids)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let message_responses: Vec<MessageResponse> = rows
         .into_iter()
-        .map(|m| MessageResponse {
-            id: m.id,
-            chat_id: m.chat_id,
-            sender: MessageSenderResponse {
-                id: m.sender_id,
-                name: m.sender_name,
-                avatar_url: m.sender_avatar,
-            },
-            content: m.content,
-            message_type: m.message_type,
-            reply_to: m.reply_to,
-            created_at: m.created_at,
+        .map(|m| {
+            let reactions = reactions.remove(&m.id).unwrap_or_default();
+
+            MessageResponse {
+                id: m.id,
+                chat_id: m.chat_id,
+                sender: MessageSenderResponse {
+                    id: m.sender_id,
+                    name: m.sender_name,
+                    avatar_url: m.sender_avatar,
+                    is_bot: m.sender_is_bot,
+                },
+                content: m.content,
+                message_type: m.message_type,
+                reply_to: m.reply_to,
+                reply_preview: m.reply_to.and_then(|id| reply_previews.get(&id).cloned()),
+                created_at: m.created_at,
+                edited_at: m.edited_at,
+                deleted_at: m.deleted_at,
+                reactions: reactions.counts,
+                my_reactions: Some(reactions.mine),
+            }
         })
         .collect();
 
@@ -76,44 +160,212 @@ pub async fn get_messages(
         "success": true,
         "data": {
             "messages": message_responses,
-            "page": page,
-            "limit": limit,
-            "total": message_responses.len()
+            "has_more_before": has_more_before,
+            "has_more_after": has_more_after
         }
     })))
 }
 
+/// Where a message sits in its chat's history, as the `(created_at, id)`
+/// pair history is ordered by.
+type MessagePosition = (DateTime<Utc>, Uuid);
+
+/// A message row joined with its sender, as history pages list it.
+struct MessageRow {
+    id: Uuid,
+    chat_id: Uuid,
+    sender_id: Uuid,
+    content: String,
+    message_type: MessageType,
+    reply_to: Option<Uuid>,
+    created_at: DateTime<Utc>,
+    edited_at: Option<DateTime<Utc>>,
+    deleted_at: Option<DateTime<Utc>>,
+    sender_name: String,
+    sender_avatar: Option<String>,
+    sender_is_bot: bool,
+}
+
+/// Looks up a cursor message, which has to belong to the chat.
+async fn message_position(
+    conn: &mut PgConnection,
+    chat_id: Uuid,
+    message_id: Uuid,
+) -> Result<MessagePosition, StatusCode> {
+    let created_at = sqlx::query_scalar!(
+        r#"SELECT created_at AS "created_at!" FROM messages WHERE id = $1 AND chat_id = $2"#,
+        message_id,
+        chat_id
+    )
+    .fetch_optional(&mut *conn)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .ok_or(StatusCode::NOT_FOUND)?;
+
+    Ok((created_at, message_id))
+}
+
+/// Messages before `before` (or the latest ones without it), newest first.
+/// `inclusive` also returns the message at `before` itself.
+async fn fetch_older(
+    conn: &mut PgConnection,
+    chat_id: Uuid,
+    viewer_id: Uuid,
+    before: Option<MessagePosition>,
+    inclusive: bool,
+    limit: i64,
+) -> sqlx::Result<Vec<MessageRow>> {
+    let (created_at, id) = before.unzip();
+
+    sqlx::query_as!(
+        MessageRow,
+        r#"
+        SELECT m.id, m.chat_id, m.sender_id, m.content,
+               m.message_type AS "message_type!: MessageType", m.reply_to,
+               m.created_at AS "created_at!", m.edited_at, m.deleted_at,
+               u.name AS sender_name, u.avatar_url AS sender_avatar, u.is_bot AS sender_is_bot
+        FROM messages m
+        JOIN users u ON m.sender_id = u.id
+        WHERE m.chat_id = $1
+          AND NOT EXISTS (SELECT 1 FROM hidden_messages h WHERE h.message_id = m.id AND h.user_id = $2)
+          AND ($3::timestamptz IS NULL
+               OR (m.created_at, m.id) < ($3, $4)
+               OR ($5 AND m.id = $4))
+        ORDER BY m.created_at DESC, m.id DESC
+        LIMIT $6
+        "#,
+        chat_id,
+        viewer_id,
+        created_at,
+        id,
+        inclusive,
+        limit
+    )
+    .fetch_all(&mut *conn)
+    .await
+}
+
+/// Messages after `after`, oldest first. `inclusive` also returns the
+/// message at `after` itself.
+async fn fetch_newer(
+    conn: &mut PgConnection,
+    chat_id: Uuid,
+    viewer_id: Uuid,
+    after: MessagePosition,
+    inclusive: bool,
+    limit: i64,
+) -> sqlx::Result<Vec<MessageRow>> {
+    sqlx::query_as!(
+        MessageRow,
+        r#"
+        SELECT m.id, m.chat_id, m.sender_id, m.content,
+               m.message_type AS "message_type!: MessageType", m.reply_to,
+               m.created_at AS "created_at!", m.edited_at, m.deleted_at,
+               u.name AS sender_name, u.avatar_url AS sender_avatar, u.is_bot AS sender_is_bot
+        FROM messages m
+        JOIN users u ON m.sender_id = u.id
+        WHERE m.chat_id = $1
+          AND NOT EXISTS (SELECT 1 FROM hidden_messages h WHERE h.message_id = m.id AND h.user_id = $2)
+          AND ((m.created_at, m.id) > ($3, $4) OR ($5 AND m.id = $4))
+        ORDER BY m.created_at ASC, m.id ASC
+        LIMIT $6
+        "#,
+        chat_id,
+        viewer_id,
+        after.0,
+        after.1,
+        inclusive,
+        limit
+    )
+    .fetch_all(&mut *conn)
+    .await
+}
+
+/// Cuts rows fetched with one extra down to `limit`, reporting whether the
+/// extra was there.
+fn take_page(mut rows: Vec<MessageRow>, limit: i64) -> (Vec<MessageRow>, bool) {
+    let has_more = rows.len() as i64 > limit;
+    rows.truncate(limit as usize);
+    (rows, has_more)
+}
+
 pub async fn send_message(
     State(state): State<AppState>,
     Extension(user_id): Extension<Uuid>,
+    api_key: Option<Extension<ApiKeyScope>>,
     Path(chat_id): Path<Uuid>,
     Json(payload): Json<SendMessageRequest>,
 ) -> Result<Json<Value>, StatusCode> {
-    // Verify user is part of the chat
-    let is_participant = sqlx::query_scalar!(
-        "SELECT EXISTS(SELECT 1 FROM chat_participants WHERE chat_id = $1 AND user_id = $2)",
+    if let Some(Extension(scope)) = &api_key {
+        scope.require(chat_id, ApiKeyPermission::SendMessages)?;
+    }
+
+    // Verify user is part of the chat and allowed to post in it
+    let participant = sqlx::query!(
+        r#"
+        SELECT cp.is_admin AS "is_admin!", c.only_admins_send_messages
+        FROM chat_participants cp
+        JOIN chats c ON c.id = cp.chat_id
+        WHERE cp.chat_id = $1 AND cp.user_id = $2
+        "#,
         chat_id,
         user_id
     )
-    .fetch_one(state.db.pool())
+    .fetch_optional(state.db.pool())
     .await
     .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
-    .unwrap_or(false);
+    .ok_or(StatusCode::FORBIDDEN)?;
 
-    if !is_participant {
+    if participant.only_admins_send_messages && !participant.is_admin {
         return Err(StatusCode::FORBIDDEN);
     }
 
+    let message_type = payload.message_type.unwrap_or(MessageType::Text);
+    if matches!(message_type, MessageType::System) {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    // Replies show part of the message they answer, so it has to be one the
+    // sender can see
+    let reply_preview = match payload.reply_to {
+        Some(reply_to) => {
+            let mut conn = state
+                .db
+                .pool()
+                .acquire()
+                .await
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+            let in_chat = sqlx::query_scalar!(
+                "SELECT EXISTS(SELECT 1 FROM messages WHERE id = $1 AND chat_id = $2)",
+                reply_to,
+                chat_id
+            )
+            .fetch_one(&mut *conn)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+            .unwrap_or(false);
+
+            if !in_chat {
+                return Err(StatusCode::BAD_REQUEST);
+            }
+
+            fetch_reply_previews(&mut conn, &[reply_to])
+                .await
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+                .remove(&reply_to)
+        }
+        None => None,
+    };
+
     let message_id = Uuid::new_v4();
     let now = chrono::Utc::now();
-    let message_type = payload.message_type.unwrap_or(MessageType::Text);
 
     // Insert message into database
     let message = sqlx::query!(
         r#"
         INSERT INTO messages (id, chat_id, sender_id, content, message_type, reply_to, created_at, updated_at)
         VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
-        RETURNING id, chat_id, sender_id, content, message_type as "message_type: MessageType", reply_to, created_at
+        RETURNING id, chat_id, sender_id, content, message_type as "message_type!: MessageType", reply_to, created_at AS "created_at!"
         "#,
         message_id,
         chat_id,
@@ -130,17 +382,18 @@ pub async fn send_message(
 
     // Get sender information
     let sender = sqlx::query!(
-        "SELECT name, avatar_url FROM users WHERE id = $1",
+        "SELECT name, avatar_url, is_bot FROM users WHERE id = $1",
         user_id
     )
     .fetch_one(state.db.pool())
     .await
     .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
-    // Update chat's updated_at timestamp
+    // Update chat's updated_at timestamp and last message
     sqlx::query!(
-        "UPDATE chats SET updated_at = $1 WHERE id = $2",
+        "UPDATE chats SET updated_at = $1, last_message_id = $2 WHERE id = $3",
         now,
+        message.id,
         chat_id
     )
     .execute(state.db.pool())
@@ -154,25 +407,661 @@ pub async fn send_message(
             id: message.sender_id,
             name: sender.name,
             avatar_url: sender.avatar_url,
+            is_bot: sender.is_bot,
         },
         content: message.content,
         message_type: message.message_type,
         reply_to: message.reply_to,
+        reply_preview,
         created_at: message.created_at,
+        edited_at: None,
+        deleted_at: None,
+        reactions: Vec::new(),
+        my_reactions: Some(Vec::new()),
     };
 
+    // Sending a message means the sender has read up to it
+    move_read_cursor(&state, chat_id, user_id, Some((message.id, message.created_at)))
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
     // Broadcast message to WebSocket clients
-    let chat_message = ChatMessage {
-        message: message_response.clone(),
+    broadcast_message(&state, message_response.clone());
+
+    Ok(Json(json!({
+        "success": true,
+        "data": message_response
+    })))
+}
+
+/// Replaces the content of one of the caller's own messages, keeping the old
+/// version as a revision, and tells everyone in the chat. Only allowed within
+/// `message_edit_window` of sending.
+pub async fn edit_message(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    api_key: Option<Extension<ApiKeyScope>>,
+    Path((chat_id, message_id)): Path<(Uuid, Uuid)>,
+    Json(payload): Json<EditMessageRequest>,
+) -> Result<Json<Value>, StatusCode> {
+    if let Some(Extension(scope)) = &api_key {
+        scope.require(chat_id, ApiKeyPermission::SendMessages)?;
+    }
+
+    if payload.content.trim().is_empty() {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let mut tx = state
+        .db
+        .pool()
+        .begin()
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let is_participant = sqlx::query_scalar!(
+        "SELECT EXISTS(SELECT 1 FROM chat_participants WHERE chat_id = $1 AND user_id = $2)",
         chat_id,
-    };
+        user_id
+    )
+    .fetch_one(&mut *tx)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .unwrap_or(false);
 
-    if let Err(e) = state.broadcast_tx.send(chat_message) {
-        tracing::warn!("Failed to broadcast message: {}", e);
+    if !is_participant {
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    let message = sqlx::query!(
+        r#"
+        SELECT sender_id, content, message_type AS "message_type!: MessageType",
+               created_at AS "created_at!", edited_at, deleted_at
+        FROM messages
+        WHERE id = $1 AND chat_id = $2
+        FOR UPDATE
+        "#,
+        message_id,
+        chat_id
+    )
+    .fetch_optional(&mut *tx)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .ok_or(StatusCode::NOT_FOUND)?;
+
+    if message.sender_id != user_id {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    if matches!(message.message_type, MessageType::System) || message.deleted_at.is_some() {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let now = Utc::now();
+    if now - message.created_at > state.config.message_edit_window {
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    let changed = payload.content != message.content;
+    if changed {
+        sqlx::query!(
+            r#"
+            INSERT INTO message_revisions (message_id, content, written_at, replaced_at)
+            VALUES ($1, $2, $3, $4)
+            "#,
+            message_id,
+            message.content,
+            message.edited_at.unwrap_or(message.created_at),
+            now
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+        sqlx::query!(
+            "UPDATE messages SET content = $1, edited_at = $2, updated_at = $2 WHERE id = $3",
+            payload.content,
+            now,
+            message_id
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    }
+
+    let message_response = fetch_message(&mut tx, message_id, user_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    tx.commit()
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    if changed {
+        ws::notify_chat(&state, chat_id, ServerEvent::MessageEdited(message_response.clone().for_everyone()));
+    }
+
+    Ok(Json(json!({
+        "success": true,
+        "data": message_response
+    })))
+}
+
+/// Deletes a message for the caller only (`?for=me`, the default), or for
+/// everyone in the chat (`?for=everyone`). Deleting for everyone is open to
+/// the sender and group admins within `message_delete_window` of sending,
+/// and leaves a tombstone in place of the message.
+pub async fn delete_message(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    api_key: Option<Extension<ApiKeyScope>>,
+    Path((chat_id, message_id)): Path<(Uuid, Uuid)>,
+    Query(params): Query<DeleteMessageQuery>,
+) -> Result<Json<Value>, StatusCode> {
+    if let Some(Extension(scope)) = &api_key {
+        scope.require(chat_id, ApiKeyPermission::SendMessages)?;
+    }
+
+    let mut tx = state
+        .db
+        .pool()
+        .begin()
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    // Group admins may delete anyone's messages; direct chats have none
+    let is_group_admin = sqlx::query_scalar!(
+        r#"
+        SELECT cp.is_admin AND c.is_group AS "is_group_admin!"
+        FROM chat_participants cp
+        JOIN chats c ON c.id = cp.chat_id
+        WHERE cp.chat_id = $1 AND cp.user_id = $2
+        "#,
+        chat_id,
+        user_id
+    )
+    .fetch_optional(&mut *tx)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .ok_or(StatusCode::FORBIDDEN)?;
+
+    let message = sqlx::query!(
+        r#"
+        SELECT sender_id, message_type AS "message_type!: MessageType", created_at AS "created_at!", deleted_at
+        FROM messages
+        WHERE id = $1 AND chat_id = $2
+        FOR UPDATE
+        "#,
+        message_id,
+        chat_id
+    )
+    .fetch_optional(&mut *tx)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .ok_or(StatusCode::NOT_FOUND)?;
+
+    if let DeleteMode::Me = params.mode {
+        sqlx::query!(
+            "INSERT INTO hidden_messages (user_id, message_id) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+            user_id,
+            message_id
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+        tx.commit()
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+        return Ok(Json(json!({
+            "success": true
+        })));
+    }
+
+    if message.sender_id != user_id && !is_group_admin {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    if matches!(message.message_type, MessageType::System) {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let now = Utc::now();
+    if now - message.created_at > state.config.message_delete_window {
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    let newly_deleted = message.deleted_at.is_none();
+    if newly_deleted {
+        sqlx::query!(
+            r#"
+            UPDATE messages
+            SET content = '', deleted_at = $1, deleted_by = $2, updated_at = $1
+            WHERE id = $3
+            "#,
+            now,
+            user_id,
+            message_id
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+        // Earlier versions would give the deleted content away
+        sqlx::query!("DELETE FROM message_revisions WHERE message_id = $1", message_id)
+            .execute(&mut *tx)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+        sqlx::query!("DELETE FROM message_reactions WHERE message_id = $1", message_id)
+            .execute(&mut *tx)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    }
+
+    let message_response = fetch_message(&mut tx, message_id, user_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    tx.commit()
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    if newly_deleted {
+        ws::notify_chat(&state, chat_id, ServerEvent::MessageDeleted(message_response.clone().for_everyone()));
     }
 
     Ok(Json(json!({
         "success": true,
         "data": message_response
     })))
-}
\ No newline at end of file
+}
+
+/// Marks a chat as read up to a message, by default the latest, and clears
+/// `marked_unread`. The read cursor never moves backwards.
+pub async fn mark_read(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    Path(chat_id): Path<Uuid>,
+    payload: Option<Json<MarkReadRequest>>,
+) -> Result<Json<Value>, StatusCode> {
+    // Verify user is part of the chat
+    let is_participant = sqlx::query_scalar!(
+        "SELECT EXISTS(SELECT 1 FROM chat_participants WHERE chat_id = $1 AND user_id = $2)",
+        chat_id,
+        user_id
+    )
+    .fetch_one(state.db.pool())
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .unwrap_or(false);
+
+    if !is_participant {
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    let read_up_to = match payload.and_then(|Json(payload)| payload.message_id) {
+        Some(message_id) => {
+            let created_at = sqlx::query_scalar!(
+                r#"SELECT created_at AS "created_at!" FROM messages WHERE id = $1 AND chat_id = $2"#,
+                message_id,
+                chat_id
+            )
+            .fetch_optional(state.db.pool())
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+            .ok_or(StatusCode::NOT_FOUND)?;
+
+            Some((message_id, created_at))
+        }
+        None => sqlx::query!(
+            r#"
+            SELECT id, created_at AS "created_at!"
+            FROM messages
+            WHERE chat_id = $1
+            ORDER BY created_at DESC, id DESC
+            LIMIT 1
+            "#,
+            chat_id
+        )
+        .fetch_optional(state.db.pool())
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .map(|m| (m.id, m.created_at)),
+    };
+
+    let cursor = move_read_cursor(&state, chat_id, user_id, read_up_to)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(json!({
+        "success": true,
+        "data": cursor
+    })))
+}
+
+/// Moves the user's read cursor forward to the message, if it's newer, clears
+/// `marked_unread` and tells the user's devices. Messages are ordered by
+/// `(created_at, id)`, so ones sent in the same instant still have an order.
+pub(crate) async fn move_read_cursor(
+    state: &AppState,
+    chat_id: Uuid,
+    user_id: Uuid,
+    message: Option<(Uuid, DateTime<Utc>)>,
+) -> anyhow::Result<ReadCursorResponse> {
+    let (message_id, created_at) = message.unzip();
+
+    let cursor = sqlx::query!(
+        r#"
+        UPDATE chat_participants
+        SET marked_unread = false,
+            last_read_message_id = CASE WHEN ($4::timestamptz, $3::uuid) > (COALESCE(last_read_at, '-infinity'), COALESCE(last_read_message_id, '00000000-0000-0000-0000-000000000000'))
+                THEN $3 ELSE last_read_message_id END,
+            last_read_at = CASE WHEN ($4::timestamptz, $3::uuid) > (COALESCE(last_read_at, '-infinity'), COALESCE(last_read_message_id, '00000000-0000-0000-0000-000000000000'))
+                THEN $4 ELSE last_read_at END
+        WHERE chat_id = $1 AND user_id = $2
+        RETURNING last_read_message_id, last_read_at
+        "#,
+        chat_id,
+        user_id,
+        message_id,
+        created_at
+    )
+    .fetch_one(state.db.pool())
+    .await?;
+
+    let cursor = ReadCursorResponse {
+        chat_id,
+        last_read_message_id: cursor.last_read_message_id,
+        last_read_at: cursor.last_read_at,
+        unread_count: count_unread(state, chat_id, user_id).await?,
+    };
+
+    ws::notify_users(state, vec![user_id], ServerEvent::ReadCursorUpdated(cursor.clone()));
+
+    Ok(cursor)
+}
+
+/// Counts messages from others after the user's read cursor, or after they
+/// joined if they haven't read anything yet. Without a cursor message the
+/// largest uuid stands in, so only strictly later timestamps count. Deleted
+/// messages, for everyone or for the user, don't count.
+pub(crate) async fn count_unread(state: &AppState, chat_id: Uuid, user_id: Uuid) -> anyhow::Result<i64> {
+    let unread_count = sqlx::query_scalar!(
+        r#"
+        SELECT COUNT(*)
+        FROM messages m
+        JOIN chat_participants cp ON cp.chat_id = m.chat_id AND cp.user_id = $2
+        WHERE m.chat_id = $1 AND m.sender_id <> $2
+          AND (m.created_at, m.id) > (
+                COALESCE(cp.last_read_at, cp.joined_at, '-infinity'),
+                COALESCE(cp.last_read_message_id, 'ffffffff-ffff-ffff-ffff-ffffffffffff')
+              )
+          AND m.deleted_at IS NULL
+          AND NOT EXISTS (SELECT 1 FROM hidden_messages h WHERE h.message_id = m.id AND h.user_id = $2)
+        "#,
+        chat_id,
+        user_id
+    )
+    .fetch_one(state.db.pool())
+    .await?
+    .unwrap_or(0);
+
+    Ok(unread_count)
+}
+
+/// Adds a system message, e.g. "Alice added Bob", to a chat's history. It is
+/// attributed to the user whose action it records.
+pub(crate) async fn insert_system_message(
+    conn: &mut PgConnection,
+    chat_id: Uuid,
+    actor_id: Uuid,
+    content: &str,
+) -> anyhow::Result<MessageResponse> {
+    let message_id = Uuid::new_v4();
+    let now = chrono::Utc::now();
+
+    sqlx::query!(
+        r#"
+        INSERT INTO messages (id, chat_id, sender_id, content, message_type, created_at, updated_at)
+        VALUES ($1, $2, $3, $4, 'system', $5, $6)
+        "#,
+        message_id,
+        chat_id,
+        actor_id,
+        content,
+        now,
+        now
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    sqlx::query!(
+        "UPDATE chats SET updated_at = $1, last_message_id = $2 WHERE id = $3",
+        now,
+        message_id,
+        chat_id
+    )
+    .execute(&mut *conn)
+    .await?;
+
+    let sender = sqlx::query!(
+        "SELECT name, avatar_url, is_bot FROM users WHERE id = $1",
+        actor_id
+    )
+    .fetch_one(&mut *conn)
+    .await?;
+
+    Ok(MessageResponse {
+        id: message_id,
+        chat_id,
+        sender: MessageSenderResponse {
+            id: actor_id,
+            name: sender.name,
+            avatar_url: sender.avatar_url,
+            is_bot: sender.is_bot,
+        },
+        content: content.to_string(),
+        message_type: MessageType::System,
+        reply_to: None,
+        reply_preview: None,
+        created_at: now,
+        edited_at: None,
+        deleted_at: None,
+        reactions: Vec::new(),
+        my_reactions: None,
+    })
+}
+
+/// Loads a message as `get_messages` returns it to `viewer_id`.
+pub(crate) async fn fetch_message(
+    conn: &mut PgConnection,
+    message_id: Uuid,
+    viewer_id: Uuid,
+) -> anyhow::Result<MessageResponse> {
+    let m = sqlx::query!(
+        r#"
+        SELECT m.chat_id, m.sender_id, m.content, m.message_type AS "message_type!: MessageType", m.reply_to,
+               m.created_at AS "created_at!", m.edited_at, m.deleted_at, u.name, u.avatar_url, u.is_bot
+        FROM messages m
+        JOIN users u ON u.id = m.sender_id
+        WHERE m.id = $1
+        "#,
+        message_id
+    )
+    .fetch_one(&mut *conn)
+    .await?;
+    let reply_preview = match m.reply_to {
+        Some(reply_to) => fetch_reply_previews(conn, &[reply_to]).await?.remove(&reply_to),
+        None => None,
+    };
+
+    let reactions = fetch_reactions(conn, &[message_id], viewer_id)
+        .await?
+        .remove(&message_id)
+        .unwrap_or_default();
+
+    Ok(MessageResponse {
+        id: message_id,
+        chat_id: m.chat_id,
+        sender: MessageSenderResponse {
+            id: m.sender_id,
+            name: m.name,
+            avatar_url: m.avatar_url,
+            is_bot: m.is_bot,
+        },
+        content: m.content,
+        message_type: m.message_type,
+        reply_to: m.reply_to,
+        reply_preview,
+        created_at: m.created_at,
+        edited_at: m.edited_at,
+        deleted_at: m.deleted_at,
+        reactions: reactions.counts,
+        my_reactions: Some(reactions.mine),
+    })
+}
+
+#[derive(Default)]
+struct MessageReactions {
+    counts: Vec<ReactionCount>,
+    mine: Vec<String>,
+}
+
+/// Reactions to each of the messages, with the ones `viewer_id` made, in
+/// one query.
+async fn fetch_reactions(
+    conn: &mut PgConnection,
+    message_ids: &[Uuid],
+    viewer_id: Uuid,
+) -> anyhow::Result<HashMap<Uuid, MessageReactions>> {
+    let rows = sqlx::query!(
+        r#"
+        SELECT message_id, emoji, COUNT(*) AS "count!", BOOL_OR(user_id = $2) AS "mine!"
+        FROM message_reactions
+        WHERE message_id = ANY($1)
+        GROUP BY message_id, emoji
+        ORDER BY MIN(created_at), emoji
+        "#,
+        message_ids,
+        viewer_id
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+
+    let mut by_message: HashMap<Uuid, MessageReactions> = HashMap::new();
+    for row in rows {
+        let reactions = by_message.entry(row.message_id).or_default();
+        if row.mine {
+            reactions.mine.push(row.emoji.clone());
+        }
+        reactions.counts.push(ReactionCount {
+            emoji: row.emoji,
+            count: row.count,
+        });
+    }
+
+    Ok(by_message)
+}
+
+/// Previews of the messages replied to, in one query. Deleted messages stay
+/// as tombstones, so a reply can still say whose message it answered.
+async fn fetch_reply_previews(
+    conn: &mut PgConnection,
+    message_ids: &[Uuid],
+) -> anyhow::Result<HashMap<Uuid, ReplyPreview>> {
+    let rows = sqlx::query!(
+        r#"
+        SELECT m.id, u.name AS sender_name, LEFT(m.content, $2) AS "snippet!", m.deleted_at
+        FROM messages m
+        JOIN users u ON u.id = m.sender_id
+        WHERE m.id = ANY($1)
+        "#,
+        message_ids,
+        REPLY_SNIPPET_CHARS
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|row| {
+            let preview = ReplyPreview {
+                sender_name: row.sender_name,
+                snippet: row.snippet,
+                is_deleted: row.deleted_at.is_some(),
+            };
+            (row.id, preview)
+        })
+        .collect())
+}
+
+/// Sends a message to the sockets open for its chat.
+pub(crate) fn broadcast_message(state: &AppState, message: MessageResponse) {
+    let chat_message = ChatMessage {
+        chat_id: message.chat_id,
+        message: message.for_everyone(),
+    };
+
+    if let Err(e) = state.broadcast_tx.send(chat_message) {
+        tracing::warn!("Failed to broadcast message: {}", e);
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rows(count: usize) -> Vec<MessageRow> {
+        (0..count)
+            .map(|i| MessageRow {
+                id: Uuid::new_v4(),
+                chat_id: Uuid::nil(),
+                sender_id: Uuid::nil(),
+                content: format!("Message {}", i),
+                message_type: MessageType::Text,
+                reply_to: None,
+                created_at: Utc::now(),
+                edited_at: None,
+                deleted_at: None,
+                sender_name: "Sender".to_string(),
+                sender_avatar: None,
+                sender_is_bot: false,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn extra_row_means_more() {
+        let fetched = rows(4);
+        let first_three: Vec<Uuid> = fetched.iter().take(3).map(|row| row.id).collect();
+
+        let (page, has_more) = take_page(fetched, 3);
+
+        assert!(has_more);
+        assert_eq!(page.iter().map(|row| row.id).collect::<Vec<_>>(), first_three);
+    }
+
+    #[test]
+    fn full_page_without_extra_is_the_last() {
+        let (page, has_more) = take_page(rows(3), 3);
+
+        assert!(!has_more);
+        assert_eq!(page.len(), 3);
+    }
+
+    #[test]
+    fn short_page_is_the_last() {
+        let (page, has_more) = take_page(rows(1), 3);
+
+        assert!(!has_more);
+        assert_eq!(page.len(), 1);
+    }
+
+    // `around` with a limit of 1 fetches no older messages, only the extra
+    #[test]
+    fn zero_limit_still_reports_more() {
+        let (page, has_more) = take_page(rows(1), 0);
+
+        assert!(has_more);
+        assert!(page.is_empty());
+    }
+}
@@ -1,3 +1,12 @@
+pub mod account;
 pub mod auth;
+pub mod bots;
 pub mod chats;
-pub mod messages;
\ No newline at end of file
+pub mod invites;
+pub mod messages;
+pub mod oidc;
+pub mod participants;
+pub mod reactions;
+pub mod search;
+pub mod sessions;
+pub mod two_factor;
\ No newline at end of file
@@ -0,0 +1,154 @@
+use axum::{extract::State, http::StatusCode, Json};
+use serde_json::{json, Value};
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::{
+    auth::{
+        oidc::{self, OidcAuthorizeResponse, OidcCallbackRequest, OidcLogin},
+        ClientInfo,
+    },
+    models::User,
+    routes::auth::{login_response, normalize_email, two_factor_challenge, NO_PASSWORD_HASH},
+    AppState,
+};
+
+/// Starts single sign-on. The frontend sends the browser to the returned URL
+/// and, once the provider redirects back, posts `code` and `state` to
+/// `callback`.
+pub async fn authorize(State(state): State<AppState>) -> Result<Json<Value>, StatusCode> {
+    let client = state.oidc.as_ref().ok_or(StatusCode::NOT_FOUND)?;
+
+    let authorization_url = oidc::start_login(&state, client).await.map_err(|e| {
+        warn!("Failed to start OIDC login: {:#}", e);
+        StatusCode::BAD_GATEWAY
+    })?;
+
+    Ok(Json(json!({
+        "success": true,
+        "data": OidcAuthorizeResponse { authorization_url }
+    })))
+}
+
+/// Finishes single sign-on and logs in the user the provider vouched for,
+/// creating or linking an account by verified email. Accounts with 2FA get
+/// the same challenge as a password login, since linking by email would
+/// otherwise let the provider stand in for the second factor.
+pub async fn callback(
+    State(state): State<AppState>,
+    client: ClientInfo,
+    Json(payload): Json<OidcCallbackRequest>,
+) -> Result<Json<Value>, StatusCode> {
+    let oidc_client = state.oidc.as_ref().ok_or(StatusCode::NOT_FOUND)?;
+
+    let login = oidc::finish_login(&state, oidc_client, &payload.code, &payload.state)
+        .await
+        .map_err(|e| {
+            warn!("OIDC login failed: {:#}", e);
+            StatusCode::UNAUTHORIZED
+        })?
+        .ok_or(StatusCode::BAD_REQUEST)?;
+
+    let user = find_or_provision_user(&state, &login).await?;
+
+    if user.has_two_factor() {
+        return two_factor_challenge(&state, &user);
+    }
+
+    login_response(&state, user, payload.device_name, &client).await
+}
+
+/// Returns the user linked to the provider account, linking or creating one
+/// by email on first sign-in. Only verified emails are trusted for this.
+async fn find_or_provision_user(state: &AppState, login: &OidcLogin) -> Result<User, StatusCode> {
+    let claims = &login.claims;
+    let email = match &claims.email {
+        Some(email) if claims.email_verified => normalize_email(email),
+        _ => return Err(StatusCode::FORBIDDEN),
+    };
+
+    let mut tx = state
+        .db
+        .pool()
+        .begin()
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let linked_user_id = sqlx::query_scalar!(
+        r#"
+        UPDATE user_identities SET email = $3, last_login_at = NOW()
+        WHERE issuer = $1 AND subject = $2
+        RETURNING user_id
+        "#,
+        login.issuer,
+        claims.sub,
+        email
+    )
+    .fetch_optional(&mut *tx)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let user = match linked_user_id {
+        Some(user_id) => sqlx::query_as::<_, User>("SELECT * FROM users WHERE id = $1")
+            .bind(user_id)
+            .fetch_one(&mut *tx)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?,
+        None => {
+            let existing = sqlx::query_as::<_, User>(
+                "UPDATE users SET email_verified_at = COALESCE(email_verified_at, NOW()) WHERE email = $1 AND NOT is_bot RETURNING *",
+            )
+            .bind(&email)
+            .fetch_optional(&mut *tx)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+            let user = match existing {
+                Some(user) => user,
+                None => {
+                    let name = claims
+                        .name
+                        .as_deref()
+                        .map(str::trim)
+                        .filter(|name| !name.is_empty())
+                        .unwrap_or_else(|| email.split('@').next().unwrap_or("User"));
+
+                    sqlx::query_as::<_, User>(
+                        r#"
+                        INSERT INTO users (id, email, name, avatar_url, password_hash, is_online, last_seen, created_at, updated_at, email_verified_at)
+                        VALUES ($1, $2, $3, $4, $5, false, NOW(), NOW(), NOW(), NOW())
+                        RETURNING *
+                        "#,
+                    )
+                    .bind(Uuid::new_v4())
+                    .bind(&email)
+                    .bind(name)
+                    .bind(&claims.picture)
+                    .bind(NO_PASSWORD_HASH)
+                    .fetch_one(&mut *tx)
+                    .await
+                    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+                }
+            };
+
+            sqlx::query!(
+                "INSERT INTO user_identities (user_id, issuer, subject, email) VALUES ($1, $2, $3, $4)",
+                user.id,
+                login.issuer,
+                claims.sub,
+                email
+            )
+            .execute(&mut *tx)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+            user
+        }
+    };
+
+    tx.commit()
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(user)
+}
@@ -0,0 +1,533 @@
+use axum::{
+    extract::{Extension, Path, State},
+    http::StatusCode,
+    Json,
+};
+use serde_json::{json, Value};
+use sqlx::{PgConnection, Postgres, Transaction};
+use uuid::Uuid;
+
+use crate::{
+    models::{AddParticipantsRequest, GroupPermissions, MembershipAction, MembershipChange, MessageResponse},
+    routes::{chats::fetch_chat, messages},
+    ws::{self, ServerEvent},
+    AppState,
+};
+
+/// Adds users to a group. Admins only, unless the group lets every member
+/// add people; users already in the group are skipped.
+pub async fn add_participants(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    Path(chat_id): Path<Uuid>,
+    Json(payload): Json<AddParticipantsRequest>,
+) -> Result<Json<Value>, StatusCode> {
+    let mut new_user_ids = payload.user_ids;
+    new_user_ids.sort();
+    new_user_ids.dedup();
+
+    if new_user_ids.is_empty() {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let mut tx = state
+        .db
+        .pool()
+        .begin()
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let group = lock_group(&mut tx, chat_id, user_id).await?;
+    if group.permissions.only_admins_add_members && !group.is_admin {
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    let added = insert_participants(&mut tx, chat_id, &new_user_ids).await?;
+
+    if !added.is_empty() {
+        let actor_name = user_name(&mut tx, user_id).await?;
+        let names: Vec<&str> = added.iter().map(|(_, name)| name.as_str()).collect();
+        let content = format!("{} added {}", actor_name, names.join(", "));
+
+        announce(&state, tx, user_id, &content, added_changes(chat_id, user_id, &added))
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    }
+
+    chat_json(&state, chat_id, user_id).await
+}
+
+/// Removes someone else from a group. Admins only; to remove yourself, leave
+/// the group.
+pub async fn remove_participant(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    Path((chat_id, member_id)): Path<(Uuid, Uuid)>,
+) -> Result<Json<Value>, StatusCode> {
+    if member_id == user_id {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let mut tx = state
+        .db
+        .pool()
+        .begin()
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    if !lock_group(&mut tx, chat_id, user_id).await?.is_admin {
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    let removed = sqlx::query!(
+        "DELETE FROM chat_participants WHERE chat_id = $1 AND user_id = $2",
+        chat_id,
+        member_id
+    )
+    .execute(&mut *tx)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .rows_affected()
+        > 0;
+
+    if !removed {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    let actor_name = user_name(&mut tx, user_id).await?;
+    let member_name = user_name(&mut tx, member_id).await?;
+    let change = MembershipChange {
+        chat_id,
+        user_id: member_id,
+        action: MembershipAction::Removed,
+        actor_id: user_id,
+    };
+
+    announce(&state, tx, user_id, &format!("{} removed {}", actor_name, member_name), vec![change])
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    chat_json(&state, chat_id, user_id).await
+}
+
+/// Makes a member of a group an admin. Admins only.
+pub async fn promote_admin(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    Path((chat_id, member_id)): Path<(Uuid, Uuid)>,
+) -> Result<Json<Value>, StatusCode> {
+    set_admin(&state, user_id, chat_id, member_id, true).await
+}
+
+/// Takes admin rights away from a member of a group, possibly the caller.
+/// Admins only; the last admin has to promote someone else first.
+pub async fn demote_admin(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    Path((chat_id, member_id)): Path<(Uuid, Uuid)>,
+) -> Result<Json<Value>, StatusCode> {
+    set_admin(&state, user_id, chat_id, member_id, false).await
+}
+
+/// Leaves a group. The last admin has to hand over to someone else first,
+/// unless nobody else is left, in which case the group is deleted.
+pub async fn leave_chat(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    Path(chat_id): Path<Uuid>,
+) -> Result<Json<Value>, StatusCode> {
+    let mut tx = state
+        .db
+        .pool()
+        .begin()
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let is_admin = lock_group(&mut tx, chat_id, user_id).await?.is_admin;
+
+    // Bots can't be admins or hand over, so only people count as remaining
+    let others = sqlx::query!(
+        r#"
+        SELECT COUNT(*) AS "members!", COUNT(*) FILTER (WHERE cp.is_admin) AS "admins!"
+        FROM chat_participants cp
+        JOIN users u ON u.id = cp.user_id
+        WHERE cp.chat_id = $1 AND cp.user_id <> $2 AND NOT u.is_bot
+        "#,
+        chat_id,
+        user_id
+    )
+    .fetch_one(&mut *tx)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let change = MembershipChange {
+        chat_id,
+        user_id,
+        action: MembershipAction::Left,
+        actor_id: user_id,
+    };
+
+    if others.members == 0 {
+        sqlx::query!("DELETE FROM chats WHERE id = $1", chat_id)
+            .execute(&mut *tx)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+        tx.commit()
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+        ws::notify_users(&state, vec![user_id], ServerEvent::MembershipChanged(change));
+    } else {
+        if is_admin && others.admins == 0 {
+            return Err(StatusCode::CONFLICT);
+        }
+
+        sqlx::query!(
+            "DELETE FROM chat_participants WHERE chat_id = $1 AND user_id = $2",
+            chat_id,
+            user_id
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+        let actor_name = user_name(&mut tx, user_id).await?;
+
+        announce(&state, tx, user_id, &format!("{} left", actor_name), vec![change])
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    }
+
+    Ok(Json(json!({
+        "success": true
+    })))
+}
+
+async fn set_admin(
+    state: &AppState,
+    user_id: Uuid,
+    chat_id: Uuid,
+    member_id: Uuid,
+    make_admin: bool,
+) -> Result<Json<Value>, StatusCode> {
+    let mut tx = state
+        .db
+        .pool()
+        .begin()
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    if !lock_group(&mut tx, chat_id, user_id).await?.is_admin {
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    let member = sqlx::query!(
+        r#"
+        SELECT cp.is_admin AS "is_admin!", u.is_bot, u.name
+        FROM chat_participants cp
+        JOIN users u ON u.id = cp.user_id
+        WHERE cp.chat_id = $1 AND cp.user_id = $2
+        "#,
+        chat_id,
+        member_id
+    )
+    .fetch_optional(&mut *tx)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .ok_or(StatusCode::NOT_FOUND)?;
+
+    if member.is_admin == make_admin {
+        return chat_json(state, chat_id, user_id).await;
+    }
+
+    if make_admin && member.is_bot {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    if !make_admin && member_id == user_id {
+        let other_admins = sqlx::query_scalar!(
+            "SELECT COUNT(*) FROM chat_participants WHERE chat_id = $1 AND user_id <> $2 AND is_admin",
+            chat_id,
+            user_id
+        )
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .unwrap_or(0);
+
+        if other_admins == 0 {
+            return Err(StatusCode::CONFLICT);
+        }
+    }
+
+    sqlx::query!(
+        "UPDATE chat_participants SET is_admin = $3 WHERE chat_id = $1 AND user_id = $2",
+        chat_id,
+        member_id,
+        make_admin
+    )
+    .execute(&mut *tx)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let actor_name = user_name(&mut tx, user_id).await?;
+    let content = if make_admin {
+        format!("{} made {} an admin", actor_name, member.name)
+    } else if member_id == user_id {
+        format!("{} is no longer an admin", actor_name)
+    } else {
+        format!("{} removed {} as an admin", actor_name, member.name)
+    };
+    let change = MembershipChange {
+        chat_id,
+        user_id: member_id,
+        action: if make_admin {
+            MembershipAction::Promoted
+        } else {
+            MembershipAction::Demoted
+        },
+        actor_id: user_id,
+    };
+
+    announce(state, tx, user_id, &content, vec![change])
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    chat_json(state, chat_id, user_id).await
+}
+
+/// Adds users to a group locked with `lock_group`, skipping current members.
+/// Returns the IDs and names of the users added.
+pub(crate) async fn insert_participants(
+    conn: &mut PgConnection,
+    chat_id: Uuid,
+    user_ids: &[Uuid],
+) -> Result<Vec<(Uuid, String)>, StatusCode> {
+    // Bots join chats through their API keys, not by being invited
+    let existing = sqlx::query_scalar!(
+        "SELECT COUNT(*) FROM users WHERE id = ANY($1) AND NOT is_bot",
+        user_ids
+    )
+    .fetch_one(&mut *conn)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .unwrap_or(0);
+
+    if existing != user_ids.len() as i64 {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    insert_members(conn, chat_id, user_ids).await
+}
+
+/// Every way of joining a group goes through here, after the caller has
+/// checked who may join. Skips current members and returns the IDs and names
+/// of the users added.
+pub(crate) async fn insert_members(
+    conn: &mut PgConnection,
+    chat_id: Uuid,
+    user_ids: &[Uuid],
+) -> Result<Vec<(Uuid, String)>, StatusCode> {
+    let added = sqlx::query!(
+        r#"
+        WITH added AS (
+            INSERT INTO chat_participants (chat_id, user_id, joined_at, is_admin)
+            SELECT $1, new_user_id, NOW(), false
+            FROM UNNEST($2::uuid[]) AS new_user_id
+            ON CONFLICT DO NOTHING
+            RETURNING user_id
+        )
+        SELECT u.id, u.name
+        FROM added
+        JOIN users u ON u.id = added.user_id
+        ORDER BY u.name
+        "#,
+        chat_id,
+        user_ids
+    )
+    .fetch_all(&mut *conn)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(added.into_iter().map(|u| (u.id, u.name)).collect())
+}
+
+pub(crate) fn added_changes(chat_id: Uuid, actor_id: Uuid, added: &[(Uuid, String)]) -> Vec<MembershipChange> {
+    added
+        .iter()
+        .map(|(user_id, _)| MembershipChange {
+            chat_id,
+            user_id: *user_id,
+            action: MembershipAction::Added,
+            actor_id,
+        })
+        .collect()
+}
+
+/// Fails unless `user_id` is an admin of the group. For read-only requests;
+/// changes go through `lock_group`.
+pub(crate) async fn ensure_group_admin(state: &AppState, chat_id: Uuid, user_id: Uuid) -> Result<(), StatusCode> {
+    let member = sqlx::query!(
+        r#"
+        SELECT c.is_group AS "is_group!", cp.is_admin AS "is_admin!"
+        FROM chats c
+        JOIN chat_participants cp ON cp.chat_id = c.id AND cp.user_id = $2
+        WHERE c.id = $1
+        "#,
+        chat_id,
+        user_id
+    )
+    .fetch_optional(state.db.pool())
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .ok_or(StatusCode::FORBIDDEN)?;
+
+    if !member.is_group {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    if !member.is_admin {
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    Ok(())
+}
+
+/// The caller's standing in a group locked with `lock_group`.
+pub(crate) struct LockedGroup {
+    pub is_admin: bool,
+    pub permissions: GroupPermissions,
+}
+
+/// Locks the group's row for the rest of the transaction, so concurrent
+/// changes can't both pass the permission checks, and returns the caller's
+/// standing in it.
+pub(crate) async fn lock_group(conn: &mut PgConnection, chat_id: Uuid, user_id: Uuid) -> Result<LockedGroup, StatusCode> {
+    let chat = sqlx::query!(
+        r#"
+        SELECT is_group AS "is_group!", only_admins_send_messages, only_admins_edit_info, only_admins_add_members
+        FROM chats
+        WHERE id = $1
+        FOR UPDATE
+        "#,
+        chat_id
+    )
+    .fetch_optional(&mut *conn)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .ok_or(StatusCode::NOT_FOUND)?;
+
+    let is_admin = sqlx::query_scalar!(
+        r#"SELECT is_admin AS "is_admin!" FROM chat_participants WHERE chat_id = $1 AND user_id = $2"#,
+        chat_id,
+        user_id
+    )
+    .fetch_optional(&mut *conn)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .ok_or(StatusCode::FORBIDDEN)?;
+
+    // Direct chats have no admins and a fixed pair of members
+    if !chat.is_group {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    Ok(LockedGroup {
+        is_admin,
+        permissions: GroupPermissions {
+            only_admins_send_messages: chat.only_admins_send_messages,
+            only_admins_edit_info: chat.only_admins_edit_info,
+            only_admins_add_members: chat.only_admins_add_members,
+        },
+    })
+}
+
+pub(crate) async fn user_name(conn: &mut PgConnection, user_id: Uuid) -> Result<String, StatusCode> {
+    sqlx::query_scalar!("SELECT name FROM users WHERE id = $1", user_id)
+        .fetch_one(&mut *conn)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+/// Commits a membership change with a system message recording it, then
+/// tells the group's members, and anyone the change took out of the group.
+/// With no changes the transaction is still committed, without a message.
+pub(crate) async fn announce(
+    state: &AppState,
+    mut tx: Transaction<'_, Postgres>,
+    actor_id: Uuid,
+    content: &str,
+    changes: Vec<MembershipChange>,
+) -> anyhow::Result<()> {
+    let announcement = record_changes(&mut tx, actor_id, content, changes).await?;
+    // Even without changes, e.g. approving a request from someone already
+    // added; the caller's other writes must still stand
+    tx.commit().await?;
+
+    if let Some(announcement) = announcement {
+        announcement.send(state);
+    }
+
+    Ok(())
+}
+
+/// A membership change recorded by `record_changes`, to send once its
+/// transaction commits.
+pub(crate) struct Announcement {
+    message: MessageResponse,
+    recipients: Vec<Uuid>,
+    changes: Vec<MembershipChange>,
+}
+
+/// Writes the system message for membership changes to one group, for callers
+/// that change several groups in one transaction. `None` without changes.
+pub(crate) async fn record_changes(
+    conn: &mut PgConnection,
+    actor_id: Uuid,
+    content: &str,
+    changes: Vec<MembershipChange>,
+) -> anyhow::Result<Option<Announcement>> {
+    let Some(chat_id) = changes.first().map(|change| change.chat_id) else {
+        return Ok(None);
+    };
+
+    let message = messages::insert_system_message(&mut *conn, chat_id, actor_id, content).await?;
+
+    let mut recipients = sqlx::query_scalar!(
+        "SELECT user_id FROM chat_participants WHERE chat_id = $1",
+        chat_id
+    )
+    .fetch_all(&mut *conn)
+    .await?;
+
+    recipients.extend(changes.iter().map(|change| change.user_id));
+    recipients.sort();
+    recipients.dedup();
+
+    Ok(Some(Announcement {
+        message,
+        recipients,
+        changes,
+    }))
+}
+
+impl Announcement {
+    pub(crate) fn send(self, state: &AppState) {
+        messages::broadcast_message(state, self.message);
+
+        for change in self.changes {
+            ws::notify_users(state, self.recipients.clone(), ServerEvent::MembershipChanged(change));
+        }
+    }
+}
+
+pub(crate) async fn chat_json(state: &AppState, chat_id: Uuid, user_id: Uuid) -> Result<Json<Value>, StatusCode> {
+    let chat = fetch_chat(state, chat_id, user_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(json!({
+        "success": true,
+        "data": chat
+    })))
+}
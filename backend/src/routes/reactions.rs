@@ -0,0 +1,175 @@
+use axum::{
+    extract::{Extension, Path, State},
+    http::StatusCode,
+    Json,
+};
+use serde_json::{json, Value};
+use uuid::Uuid;
+
+use crate::{
+    models::{MessageType, ReactionChange},
+    ws::{self, ServerEvent},
+    AppState,
+};
+
+/// Longest emoji accepted, in bytes. Enough for ZWJ sequences such as
+/// family emoji with skin tones.
+const MAX_EMOJI_LEN: usize = 32;
+
+/// Reacts to a message with an emoji. Reacting twice with the same emoji is
+/// a no-op; different emoji add up.
+pub async fn add_reaction(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    Path((chat_id, message_id, emoji)): Path<(Uuid, Uuid, String)>,
+) -> Result<Json<Value>, StatusCode> {
+    if !is_emoji(&emoji) {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    ensure_participant(&state, chat_id, user_id).await?;
+
+    let message = sqlx::query!(
+        r#"
+        SELECT message_type AS "message_type!: MessageType", deleted_at
+        FROM messages
+        WHERE id = $1 AND chat_id = $2
+        "#,
+        message_id,
+        chat_id
+    )
+    .fetch_optional(state.db.pool())
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .ok_or(StatusCode::NOT_FOUND)?;
+
+    if matches!(message.message_type, MessageType::System) || message.deleted_at.is_some() {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let added = sqlx::query!(
+        r#"
+        INSERT INTO message_reactions (message_id, user_id, emoji)
+        VALUES ($1, $2, $3)
+        ON CONFLICT DO NOTHING
+        "#,
+        message_id,
+        user_id,
+        emoji
+    )
+    .execute(state.db.pool())
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .rows_affected()
+        > 0;
+
+    if added {
+        let change = ReactionChange {
+            chat_id,
+            message_id,
+            user_id,
+            emoji,
+        };
+        ws::notify_chat(&state, chat_id, ServerEvent::ReactionAdded(change));
+    }
+
+    Ok(Json(json!({
+        "success": true
+    })))
+}
+
+/// Takes back one of the caller's reactions. Removing a reaction that isn't
+/// there is a no-op.
+pub async fn remove_reaction(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    Path((chat_id, message_id, emoji)): Path<(Uuid, Uuid, String)>,
+) -> Result<Json<Value>, StatusCode> {
+    ensure_participant(&state, chat_id, user_id).await?;
+
+    let removed = sqlx::query!(
+        r#"
+        DELETE FROM message_reactions r
+        USING messages m
+        WHERE r.message_id = $1 AND r.user_id = $2 AND r.emoji = $3
+          AND m.id = r.message_id AND m.chat_id = $4
+        "#,
+        message_id,
+        user_id,
+        emoji,
+        chat_id
+    )
+    .execute(state.db.pool())
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .rows_affected()
+        > 0;
+
+    if removed {
+        let change = ReactionChange {
+            chat_id,
+            message_id,
+            user_id,
+            emoji,
+        };
+        ws::notify_chat(&state, chat_id, ServerEvent::ReactionRemoved(change));
+    }
+
+    Ok(Json(json!({
+        "success": true
+    })))
+}
+
+async fn ensure_participant(state: &AppState, chat_id: Uuid, user_id: Uuid) -> Result<(), StatusCode> {
+    let is_participant = sqlx::query_scalar!(
+        "SELECT EXISTS(SELECT 1 FROM chat_participants WHERE chat_id = $1 AND user_id = $2)",
+        chat_id,
+        user_id
+    )
+    .fetch_one(state.db.pool())
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    .unwrap_or(false);
+
+    if !is_participant {
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    Ok(())
+}
+
+/// A rough check that `text` is a single emoji rather than words: short, and
+/// without letters, spaces or control characters. Keycaps such as "1️⃣"
+/// contain a digit, so digits are allowed.
+fn is_emoji(text: &str) -> bool {
+    !text.is_empty()
+        && text.len() <= MAX_EMOJI_LEN
+        && !text
+            .chars()
+            .any(|c| c.is_alphabetic() || c.is_whitespace() || c.is_control())
+        && !text.is_ascii()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_emoji() {
+        for emoji in ["👍", "❤️", "🎉", "👍🏽", "👨‍👩‍👧", "🇨🇲", "1️⃣"] {
+            assert!(is_emoji(emoji), "{}", emoji);
+        }
+    }
+
+    #[test]
+    fn rejects_text() {
+        for text in ["", "ok", "1", ":)", "👍 ", "👍a", "é", "\u{7}"] {
+            assert!(!is_emoji(text), "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn rejects_long_strings() {
+        assert!(!is_emoji(&"🎉".repeat(MAX_EMOJI_LEN)));
+    }
+}
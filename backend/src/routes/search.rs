@@ -0,0 +1,193 @@
+use axum::{
+    extract::{Extension, Query, State},
+    http::StatusCode,
+    Json,
+};
+use serde_json::{json, Value};
+use uuid::Uuid;
+
+use crate::{
+    models::{MessageSearchResult, MessageSenderResponse, MessageType, SearchMessagesQuery},
+    AppState,
+};
+
+/// Results returned per page when the request doesn't say.
+const DEFAULT_SEARCH_PAGE_SIZE: i64 = 20;
+/// Most results returned per page.
+const MAX_SEARCH_PAGE_SIZE: i64 = 50;
+
+/// Searches the content of messages in the caller's chats, newest first.
+/// Messages deleted for everyone or hidden by the caller are left out.
+pub async fn search_messages(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    Query(params): Query<SearchMessagesQuery>,
+) -> Result<Json<Value>, StatusCode> {
+    if params.q.trim().is_empty() {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let limit = params
+        .limit
+        .map_or(DEFAULT_SEARCH_PAGE_SIZE, i64::from)
+        .clamp(1, MAX_SEARCH_PAGE_SIZE);
+
+    if let Some(chat_id) = params.chat_id {
+        let is_participant = sqlx::query_scalar!(
+            "SELECT EXISTS(SELECT 1 FROM chat_participants WHERE chat_id = $1 AND user_id = $2)",
+            chat_id,
+            user_id
+        )
+        .fetch_one(state.db.pool())
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .unwrap_or(false);
+
+        if !is_participant {
+            return Err(StatusCode::FORBIDDEN);
+        }
+    }
+
+    // The cursor has to be a message the caller can see
+    let before = match params.before {
+        Some(message_id) => {
+            let created_at = sqlx::query_scalar!(
+                r#"
+                SELECT m.created_at AS "created_at!"
+                FROM messages m
+                JOIN chat_participants cp ON cp.chat_id = m.chat_id AND cp.user_id = $2
+                WHERE m.id = $1
+                "#,
+                message_id,
+                user_id
+            )
+            .fetch_optional(state.db.pool())
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+            .ok_or(StatusCode::NOT_FOUND)?;
+
+            Some((created_at, message_id))
+        }
+        None => None,
+    };
+    let (before_created_at, before_id) = before.unzip();
+
+    // ts_headline would take HTML special characters for markup, and
+    // escaping them first would let a search for "amp" match inside "&amp;".
+    // So they, and the match markers, stand in as control characters that
+    // `snippet_html` turns into HTML afterwards; real control characters
+    // in the content are dropped.
+    let mut rows = sqlx::query!(
+        r#"
+        SELECT m.id, m.chat_id, m.sender_id, m.content,
+               m.message_type AS "message_type!: MessageType", m.reply_to,
+               m.created_at AS "created_at!", m.edited_at,
+               u.name AS sender_name, u.avatar_url AS sender_avatar, u.is_bot AS sender_is_bot,
+               ts_headline(
+                   'simple',
+                   translate(m.content, '&<>' || chr(2) || chr(3) || chr(4) || chr(5) || chr(6), chr(4) || chr(5) || chr(6)),
+                   query,
+                   'StartSel=' || chr(2) || ', StopSel=' || chr(3) || ', MinWords=5, MaxWords=20, MaxFragments=2'
+               ) AS "snippet!"
+        FROM websearch_to_tsquery('simple', $2) AS query,
+             messages m
+        JOIN chat_participants cp ON cp.chat_id = m.chat_id AND cp.user_id = $1
+        JOIN users u ON m.sender_id = u.id
+        WHERE m.content_search @@ query
+          AND m.deleted_at IS NULL
+          AND NOT EXISTS (SELECT 1 FROM hidden_messages h WHERE h.message_id = m.id AND h.user_id = $1)
+          AND ($3::uuid IS NULL OR m.chat_id = $3)
+          AND ($4::uuid IS NULL OR m.sender_id = $4)
+          AND ($5::timestamptz IS NULL OR m.created_at >= $5)
+          AND ($6::timestamptz IS NULL OR m.created_at < $6)
+          AND ($7::message_type IS NULL OR m.message_type = $7)
+          AND ($8::timestamptz IS NULL OR (m.created_at, m.id) < ($8, $9))
+        ORDER BY m.created_at DESC, m.id DESC
+        LIMIT $10
+        "#,
+        user_id,
+        params.q,
+        params.chat_id,
+        params.sender_id,
+        params.from,
+        params.to,
+        params.message_type as Option<MessageType>,
+        before_created_at,
+        before_id,
+        limit + 1
+    )
+    .fetch_all(state.db.pool())
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let has_more = rows.len() as i64 > limit;
+    rows.truncate(limit as usize);
+
+    let results: Vec<MessageSearchResult> = rows
+        .into_iter()
+        .map(|m| MessageSearchResult {
+            id: m.id,
+            chat_id: m.chat_id,
+            sender: MessageSenderResponse {
+                id: m.sender_id,
+                name: m.sender_name,
+                avatar_url: m.sender_avatar,
+                is_bot: m.sender_is_bot,
+            },
+            content: m.content,
+            message_type: m.message_type,
+            reply_to: m.reply_to,
+            created_at: m.created_at,
+            edited_at: m.edited_at,
+            snippet: snippet_html(&m.snippet),
+        })
+        .collect();
+
+    Ok(Json(json!({
+        "success": true,
+        "data": {
+            "messages": results,
+            "has_more": has_more
+        }
+    })))
+}
+
+/// Turns a headline from `search_messages` into HTML: the stand-ins for
+/// `&`, `<` and `>` become entities and the match markers `<mark>` tags.
+fn snippet_html(headline: &str) -> String {
+    let mut html = String::with_capacity(headline.len());
+    for c in headline.chars() {
+        match c {
+            '\u{2}' => html.push_str("<mark>"),
+            '\u{3}' => html.push_str("</mark>"),
+            '\u{4}' | '&' => html.push_str("&amp;"),
+            '\u{5}' | '<' => html.push_str("&lt;"),
+            '\u{6}' | '>' => html.push_str("&gt;"),
+            c => html.push(c),
+        }
+    }
+    html
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn marks_matches() {
+        assert_eq!(snippet_html("say \u{2}hello\u{3} there"), "say <mark>hello</mark> there");
+    }
+
+    #[test]
+    fn escapes_stand_ins() {
+        assert_eq!(
+            snippet_html("\u{5}b\u{6}Tom \u{4} \u{2}Jerry\u{3}\u{5}/b\u{6}"),
+            "&lt;b&gt;Tom &amp; <mark>Jerry</mark>&lt;/b&gt;"
+        );
+    }
+
+    #[test]
+    fn escapes_stray_special_characters() {
+        assert_eq!(snippet_html("<script>&"), "&lt;script&gt;&amp;");
+    }
+}
@@ -0,0 +1,67 @@
+use axum::{
+    extract::{Extension, Path, State},
+    http::StatusCode,
+    Json,
+};
+use serde_json::{json, Value};
+use uuid::Uuid;
+
+use crate::{
+    auth::{sessions, CurrentSession},
+    models::{session::SessionResponse, Session},
+    AppState,
+};
+
+pub async fn get_sessions(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    Extension(CurrentSession(current_session_id)): Extension<CurrentSession>,
+) -> Result<Json<Value>, StatusCode> {
+    let sessions = sqlx::query_as::<_, Session>(
+        r#"
+        SELECT * FROM sessions
+        WHERE user_id = $1 AND revoked_at IS NULL AND expires_at > NOW()
+        ORDER BY last_active_at DESC
+        "#
+    )
+    .bind(user_id)
+    .fetch_all(state.db.pool())
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let session_responses: Vec<SessionResponse> = sessions
+        .into_iter()
+        .map(|s| SessionResponse {
+            is_current: s.id == current_session_id,
+            id: s.id,
+            device_name: s.device_name,
+            user_agent: s.user_agent,
+            ip_address: s.ip_address,
+            created_at: s.created_at,
+            last_active_at: s.last_active_at,
+        })
+        .collect();
+
+    Ok(Json(json!({
+        "success": true,
+        "data": session_responses
+    })))
+}
+
+pub async fn delete_session(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    Path(session_id): Path<Uuid>,
+) -> Result<Json<Value>, StatusCode> {
+    let revoked = sessions::revoke_session(&state, user_id, session_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    if !revoked {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    Ok(Json(json!({
+        "success": true
+    })))
+}
@@ -0,0 +1,199 @@
+use axum::{
+    extract::{Extension, State},
+    http::StatusCode,
+    Json,
+};
+use serde_json::{json, Value};
+use uuid::Uuid;
+
+use crate::{
+    auth::totp,
+    models::{
+        user::{TwoFactorCodeRequest, TwoFactorEnrollmentResponse},
+        User,
+    },
+    AppState,
+};
+
+/// Starts enrollment by generating a new secret. 2FA isn't enforced until
+/// the secret is confirmed with a first code.
+pub async fn enroll(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+) -> Result<Json<Value>, StatusCode> {
+    let user = fetch_user(&state, user_id).await?;
+
+    if user.has_two_factor() {
+        return Err(StatusCode::CONFLICT);
+    }
+
+    let secret = totp::generate_secret();
+    let otpauth_uri = totp::otpauth_uri(&secret, &state.config.totp_issuer, &user.email)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    sqlx::query!(
+        "UPDATE users SET totp_secret = $1, totp_last_used_step = NULL, updated_at = NOW() WHERE id = $2",
+        secret,
+        user_id
+    )
+    .execute(state.db.pool())
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(json!({
+        "success": true,
+        "data": TwoFactorEnrollmentResponse { secret, otpauth_uri }
+    })))
+}
+
+/// Turns 2FA on once the user proves their authenticator works, and returns
+/// recovery codes. The codes are only ever shown here.
+pub async fn confirm(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    Json(payload): Json<TwoFactorCodeRequest>,
+) -> Result<Json<Value>, StatusCode> {
+    let user = fetch_user(&state, user_id).await?;
+
+    if user.has_two_factor() {
+        return Err(StatusCode::CONFLICT);
+    }
+    if user.totp_secret.is_none() {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let verified = verify_second_factor(&state, &user, &payload.code)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if !verified {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+
+    let recovery_codes = totp::generate_recovery_codes();
+
+    let mut tx = state.db.pool().begin().await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    sqlx::query!(
+        "UPDATE users SET totp_enabled_at = NOW(), updated_at = NOW() WHERE id = $1",
+        user_id
+    )
+    .execute(&mut *tx)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    sqlx::query!("DELETE FROM recovery_codes WHERE user_id = $1", user_id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    for code in &recovery_codes {
+        sqlx::query!(
+            "INSERT INTO recovery_codes (user_id, code_hash) VALUES ($1, $2)",
+            user_id,
+            totp::hash_recovery_code(code)
+        )
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    }
+
+    tx.commit().await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(json!({
+        "success": true,
+        "data": {
+            "recovery_codes": recovery_codes
+        }
+    })))
+}
+
+pub async fn disable(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    Json(payload): Json<TwoFactorCodeRequest>,
+) -> Result<Json<Value>, StatusCode> {
+    let user = fetch_user(&state, user_id).await?;
+
+    if !user.has_two_factor() {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let verified = verify_second_factor(&state, &user, &payload.code)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if !verified {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+
+    let mut tx = state.db.pool().begin().await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    sqlx::query!(
+        r#"
+        UPDATE users
+        SET totp_secret = NULL, totp_enabled_at = NULL, totp_last_used_step = NULL, updated_at = NOW()
+        WHERE id = $1
+        "#,
+        user_id
+    )
+    .execute(&mut *tx)
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    sqlx::query!("DELETE FROM recovery_codes WHERE user_id = $1", user_id)
+        .execute(&mut *tx)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    tx.commit().await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(json!({
+        "success": true
+    })))
+}
+
+/// Accepts either a TOTP code or an unused recovery code, consuming it so it
+/// can't be used a second time.
+pub(crate) async fn verify_second_factor(state: &AppState, user: &User, code: &str) -> anyhow::Result<bool> {
+    let Some(secret) = user.totp_secret.as_deref() else {
+        return Ok(false);
+    };
+
+    if let Some(step) = totp::verify_code(secret, code, user.totp_last_used_step)? {
+        // Claim the step atomically so concurrent requests can't share a code
+        let claimed = sqlx::query!(
+            r#"
+            UPDATE users SET totp_last_used_step = $2
+            WHERE id = $1 AND (totp_last_used_step IS NULL OR totp_last_used_step < $2)
+            "#,
+            user.id,
+            step
+        )
+        .execute(state.db.pool())
+        .await?
+        .rows_affected()
+            > 0;
+
+        return Ok(claimed);
+    }
+
+    let used = sqlx::query!(
+        "UPDATE recovery_codes SET used_at = NOW() WHERE user_id = $1 AND code_hash = $2 AND used_at IS NULL",
+        user.id,
+        totp::hash_recovery_code(code)
+    )
+    .execute(state.db.pool())
+    .await?
+    .rows_affected()
+        > 0;
+
+    Ok(used)
+}
+
+pub(crate) async fn fetch_user(state: &AppState, user_id: Uuid) -> Result<User, StatusCode> {
+    sqlx::query_as::<_, User>("SELECT * FROM users WHERE id = $1")
+        .bind(user_id)
+        .fetch_optional(state.db.pool())
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::UNAUTHORIZED)
+}
@@ -0,0 +1,399 @@
+use anyhow::{anyhow, bail, Context, Result};
+use bcrypt::{hash, DEFAULT_COST};
+use chrono::{DateTime, Duration, Utc};
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+use std::collections::{HashMap, HashSet};
+use tracing::info;
+use uuid::{Builder, Uuid};
+
+use crate::{db::Database, routes::chats::direct_pair};
+
+/// Password of every seeded account.
+pub const SEED_PASSWORD: &str = "password123";
+
+/// Seeded activity is spread over this many days before now.
+const HISTORY_DAYS: i64 = 30;
+
+const FIRST_NAMES: &[&str] = &[
+    "Alice", "Bob", "Sarah", "Daniel", "Amina", "Paul", "Grace", "Samuel", "Fatima", "Joseph",
+    "Esther", "Michael", "Linda", "Eric", "Nadia", "Patrick", "Ruth", "Kevin", "Claire", "Ibrahim",
+];
+
+const LAST_NAMES: &[&str] = &[
+    "Johnson", "Smith", "Wilson", "Ngono", "Fotso", "Mbarga", "Tchoupo", "Essomba", "Nkeng", "Bello",
+    "Atangana", "Kamga", "Brown", "Eto'o", "Abena", "Manga",
+];
+
+const GROUP_NAMES: &[&str] = &[
+    "Family", "Weekend Plans", "Project Team", "Book Club", "Football Fans", "Study Group",
+    "Neighbours", "Road Trip", "Church Choir", "Startup Ideas", "Class of 2015", "Foodies",
+];
+
+const MESSAGES: &[&str] = &[
+    "Hey! How are you doing?",
+    "Thanks for the help yesterday!",
+    "Can you send me the documents?",
+    "On my way",
+    "See you tomorrow",
+    "Did you watch the match last night?",
+    "Let's meet at 6pm",
+    "Sounds good to me",
+    "I'll call you later",
+    "Happy birthday! 🎉",
+    "Where are we meeting?",
+    "Running a bit late, sorry",
+    "Great news!",
+    "Can't wait for the weekend",
+    "Who's bringing the drinks?",
+    "Good morning everyone",
+];
+
+/// How much demo data `seed` generates. The same options always produce the
+/// same users, chats and messages.
+#[derive(Debug, Clone)]
+pub struct SeedOptions {
+    pub users: usize,
+    pub chats: usize,
+    pub messages_per_chat: usize,
+    pub seed: u64,
+}
+
+impl Default for SeedOptions {
+    fn default() -> Self {
+        Self {
+            users: 20,
+            chats: 30,
+            messages_per_chat: 50,
+            seed: 42,
+        }
+    }
+}
+
+impl SeedOptions {
+    /// Parses `--users N`, `--chats N`, `--messages N` and `--seed N`; the
+    /// rest keep their defaults.
+    pub fn from_args(args: impl IntoIterator<Item = String>) -> Result<Self> {
+        let mut options = Self::default();
+        let mut args = args.into_iter();
+
+        while let Some(flag) = args.next() {
+            let value = args.next().ok_or_else(|| anyhow!("{} needs a value", flag))?;
+            let number = || value.parse::<u64>().with_context(|| format!("invalid {} {:?}", flag, value));
+
+            match flag.as_str() {
+                "--users" => options.users = number()? as usize,
+                "--chats" => options.chats = number()? as usize,
+                "--messages" => options.messages_per_chat = number()? as usize,
+                "--seed" => options.seed = number()?,
+                _ => bail!("unknown option {}", flag),
+            }
+        }
+
+        if options.users < 2 && options.chats > 0 {
+            bail!("chats need at least 2 users");
+        }
+
+        Ok(options)
+    }
+}
+
+/// Fills the database with demo users, direct and group chats, and message
+/// histories. Users get the emails `user1@example.com`, `user2@example.com`,
+/// ... and the password `SEED_PASSWORD`. Fails without writing anything if
+/// one of those accounts already exists.
+pub async fn seed(db: &Database, options: &SeedOptions) -> Result<()> {
+    let mut rng = StdRng::seed_from_u64(options.seed);
+    let now = Utc::now();
+    let start = now - Duration::days(HISTORY_DAYS);
+
+    // bcrypt is deliberately slow, so every account shares one hash
+    let password_hash = hash(SEED_PASSWORD, DEFAULT_COST)?;
+
+    let mut users = Users::default();
+    for i in 1..=options.users {
+        let first = FIRST_NAMES.choose(&mut rng).copied().unwrap_or("User");
+        let last = LAST_NAMES.choose(&mut rng).copied().unwrap_or("Seed");
+
+        users.ids.push(random_uuid(&mut rng));
+        users.emails.push(format!("user{}@example.com", i));
+        users.names.push(format!("{} {}", first, last));
+        users.is_online.push(rng.gen_bool(0.3));
+        users.last_seen.push(random_time(&mut rng, start, now));
+    }
+
+    let mut chats = Chats::default();
+    let mut participants = Participants::default();
+    let mut messages = Messages::default();
+    let mut direct_pairs = HashSet::new();
+
+    for _ in 0..options.chats {
+        let chat_id = random_uuid(&mut rng);
+        let created_at = random_time(&mut rng, start, now - Duration::days(1));
+
+        // Every third chat is a group; the rest are direct chats between
+        // users that don't have one yet
+        let (members, name, pair) = if rng.gen_ratio(1, 3) || direct_pairs.len() >= max_direct_pairs(options.users) {
+            let size = rng.gen_range(3..=8).min(options.users);
+            let members: Vec<usize> = rand::seq::index::sample(&mut rng, options.users, size).into_vec();
+            let name = GROUP_NAMES.choose(&mut rng).map(|name| name.to_string());
+            (members, name, None)
+        } else {
+            loop {
+                let picked = rand::seq::index::sample(&mut rng, options.users, 2).into_vec();
+                let pair = direct_pair(users.ids[picked[0]], users.ids[picked[1]]);
+                if direct_pairs.insert(pair) {
+                    break (picked, None, Some(pair));
+                }
+            }
+        };
+
+        // Messages are spread between the chat's creation and now, in order
+        let mut timestamps: Vec<DateTime<Utc>> = (0..options.messages_per_chat)
+            .map(|_| random_time(&mut rng, created_at, now))
+            .collect();
+        timestamps.sort();
+
+        let mut last_message_id = None;
+        let mut last_sent = HashMap::new();
+        for sent_at in timestamps {
+            let message_id = random_uuid(&mut rng);
+            let sender = members.choose(&mut rng).copied().unwrap_or(members[0]);
+
+            messages.ids.push(message_id);
+            messages.chat_ids.push(chat_id);
+            messages.sender_ids.push(users.ids[sender]);
+            messages.contents.push(MESSAGES.choose(&mut rng).copied().unwrap_or_default().to_string());
+            messages.created_at.push(sent_at);
+            last_message_id = Some((message_id, sent_at));
+            last_sent.insert(sender, (message_id, sent_at));
+        }
+
+        // Members have read the chat up to the last message they sent, so
+        // only what came after it is unread
+        let creator = users.ids[members[0]];
+        for &member in &members {
+            let read_up_to = last_sent.get(&member);
+            participants.chat_ids.push(chat_id);
+            participants.user_ids.push(users.ids[member]);
+            participants.joined_at.push(created_at);
+            participants.is_admin.push(name.is_some() && users.ids[member] == creator);
+            participants.last_read_message_ids.push(read_up_to.map(|(id, _)| *id));
+            participants.last_read_at.push(read_up_to.map_or(created_at, |(_, at)| *at));
+        }
+
+        chats.ids.push(chat_id);
+        chats.is_group.push(name.is_some());
+        chats.names.push(name);
+        chats.created_by.push(creator);
+        chats.created_at.push(created_at);
+        chats.updated_at.push(last_message_id.map_or(created_at, |(_, at)| at));
+        chats.last_message_ids.push(last_message_id.map(|(id, _)| id));
+        chats.direct_user_a.push(pair.map(|(a, _)| a));
+        chats.direct_user_b.push(pair.map(|(_, b)| b));
+    }
+
+    let mut tx = db.pool().begin().await?;
+
+    let existing = sqlx::query_scalar!("SELECT email FROM users WHERE email = ANY($1) LIMIT 1", &users.emails)
+        .fetch_optional(&mut *tx)
+        .await?;
+    if let Some(email) = existing {
+        bail!("{} already exists; the database has been seeded before", email);
+    }
+
+    sqlx::query!(
+        r#"
+        INSERT INTO users (id, email, name, password_hash, is_online, last_seen, email_verified_at, created_at, updated_at)
+        SELECT id, email, name, $6, is_online, last_seen, $7, $7, $7
+        FROM UNNEST($1::uuid[], $2::text[], $3::text[], $4::bool[], $5::timestamptz[])
+            AS u(id, email, name, is_online, last_seen)
+        "#,
+        &users.ids,
+        &users.emails,
+        &users.names,
+        &users.is_online,
+        &users.last_seen,
+        password_hash,
+        start
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    // Chats reference their last message, so they go in before it is set
+    sqlx::query!(
+        r#"
+        INSERT INTO chats (id, name, is_group, created_by, created_at, updated_at, direct_user_a, direct_user_b)
+        SELECT * FROM UNNEST($1::uuid[], $2::text[], $3::bool[], $4::uuid[], $5::timestamptz[],
+                             $6::timestamptz[], $7::uuid[], $8::uuid[])
+        "#,
+        &chats.ids,
+        &chats.names as &[Option<String>],
+        &chats.is_group,
+        &chats.created_by,
+        &chats.created_at,
+        &chats.updated_at,
+        &chats.direct_user_a as &[Option<Uuid>],
+        &chats.direct_user_b as &[Option<Uuid>]
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    sqlx::query!(
+        r#"
+        INSERT INTO messages (id, chat_id, sender_id, content, message_type, created_at, updated_at)
+        SELECT id, chat_id, sender_id, content, 'text', created_at, created_at
+        FROM UNNEST($1::uuid[], $2::uuid[], $3::uuid[], $4::text[], $5::timestamptz[])
+            AS m(id, chat_id, sender_id, content, created_at)
+        "#,
+        &messages.ids,
+        &messages.chat_ids,
+        &messages.sender_ids,
+        &messages.contents,
+        &messages.created_at
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    // Participants point at the message they have read up to
+    sqlx::query!(
+        r#"
+        INSERT INTO chat_participants (chat_id, user_id, joined_at, is_admin, last_read_message_id, last_read_at)
+        SELECT * FROM UNNEST($1::uuid[], $2::uuid[], $3::timestamptz[], $4::bool[], $5::uuid[], $6::timestamptz[])
+        "#,
+        &participants.chat_ids,
+        &participants.user_ids,
+        &participants.joined_at,
+        &participants.is_admin,
+        &participants.last_read_message_ids as &[Option<Uuid>],
+        &participants.last_read_at
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    sqlx::query!(
+        r#"
+        UPDATE chats c
+        SET last_message_id = seeded.last_message_id
+        FROM UNNEST($1::uuid[], $2::uuid[]) AS seeded(chat_id, last_message_id)
+        WHERE c.id = seeded.chat_id
+        "#,
+        &chats.ids,
+        &chats.last_message_ids as &[Option<Uuid>]
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    info!(
+        "Seeded {} users, {} chats and {} messages (seed {})",
+        users.ids.len(),
+        chats.ids.len(),
+        messages.ids.len(),
+        options.seed
+    );
+
+    Ok(())
+}
+
+// Rows are collected column by column so each table is written with a
+// single `UNNEST` insert.
+
+#[derive(Default)]
+struct Users {
+    ids: Vec<Uuid>,
+    emails: Vec<String>,
+    names: Vec<String>,
+    is_online: Vec<bool>,
+    last_seen: Vec<DateTime<Utc>>,
+}
+
+#[derive(Default)]
+struct Chats {
+    ids: Vec<Uuid>,
+    names: Vec<Option<String>>,
+    is_group: Vec<bool>,
+    created_by: Vec<Uuid>,
+    created_at: Vec<DateTime<Utc>>,
+    updated_at: Vec<DateTime<Utc>>,
+    last_message_ids: Vec<Option<Uuid>>,
+    direct_user_a: Vec<Option<Uuid>>,
+    direct_user_b: Vec<Option<Uuid>>,
+}
+
+#[derive(Default)]
+struct Participants {
+    chat_ids: Vec<Uuid>,
+    user_ids: Vec<Uuid>,
+    joined_at: Vec<DateTime<Utc>>,
+    is_admin: Vec<bool>,
+    last_read_message_ids: Vec<Option<Uuid>>,
+    last_read_at: Vec<DateTime<Utc>>,
+}
+
+#[derive(Default)]
+struct Messages {
+    ids: Vec<Uuid>,
+    chat_ids: Vec<Uuid>,
+    sender_ids: Vec<Uuid>,
+    contents: Vec<String>,
+    created_at: Vec<DateTime<Utc>>,
+}
+
+/// A v4 UUID drawn from `rng`, so ids are reproducible too.
+fn random_uuid(rng: &mut StdRng) -> Uuid {
+    Builder::from_random_bytes(rng.gen()).into_uuid()
+}
+
+fn random_time(rng: &mut StdRng, from: DateTime<Utc>, to: DateTime<Utc>) -> DateTime<Utc> {
+    let span = (to - from).num_seconds().max(1);
+    from + Duration::seconds(rng.gen_range(0..span))
+}
+
+fn max_direct_pairs(users: usize) -> usize {
+    users * users.saturating_sub(1) / 2
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<SeedOptions> {
+        SeedOptions::from_args(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn defaults_without_arguments() {
+        let options = parse(&[]).unwrap();
+        let defaults = SeedOptions::default();
+
+        assert_eq!(options.users, defaults.users);
+        assert_eq!(options.chats, defaults.chats);
+        assert_eq!(options.messages_per_chat, defaults.messages_per_chat);
+        assert_eq!(options.seed, defaults.seed);
+    }
+
+    #[test]
+    fn parses_every_option() {
+        let options = parse(&["--users", "5", "--chats", "7", "--messages", "3", "--seed", "9"]).unwrap();
+
+        assert_eq!(options.users, 5);
+        assert_eq!(options.chats, 7);
+        assert_eq!(options.messages_per_chat, 3);
+        assert_eq!(options.seed, 9);
+    }
+
+    #[test]
+    fn rejects_bad_arguments() {
+        assert!(parse(&["--users"]).is_err());
+        assert!(parse(&["--users", "many"]).is_err());
+        assert!(parse(&["--users", "-1"]).is_err());
+        assert!(parse(&["--colour", "blue"]).is_err());
+    }
+
+    #[test]
+    fn chats_need_two_users() {
+        assert!(parse(&["--users", "1"]).is_err());
+        assert!(parse(&["--users", "1", "--chats", "0"]).is_ok());
+    }
+}
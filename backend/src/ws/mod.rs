@@ -1,19 +1,27 @@
 use axum::{
     extract::{
-        ws::{Message, WebSocket, WebSocketUpgrade},
-        Extension, Path, Query, State,
+        ws::{close_code, CloseFrame, Message, WebSocket, WebSocketUpgrade},
+        Path, Query, State,
     },
-    http::StatusCode,
+    http::{header::SEC_WEBSOCKET_PROTOCOL, HeaderMap, StatusCode},
     response::Response,
 };
 use futures_util::{sink::SinkExt, stream::StreamExt};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
 use tracing::{error, info, warn};
 use uuid::Uuid;
 
-use crate::{auth::verify_token, models::MessageResponse, AppState};
+use crate::{
+    auth::{sessions, verify_token},
+    models::{
+        invite::JoinRequestResponse, ChatResponse, MembershipAction, MembershipChange, MessageResponse, ReactionChange,
+        ReadCursorResponse,
+    },
+    AppState,
+};
+
+pub mod tickets;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ChatMessage {
@@ -21,21 +29,86 @@ pub struct ChatMessage {
     pub chat_id: Uuid,
 }
 
+/// Events besides chat messages, sent either to particular users, e.g. being
+/// added to a new chat, or to a chat's sockets, e.g. an edited message.
+/// Serialized as `{"type": ..., "data": ...}`.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", content = "data", rename_all = "snake_case")]
+pub enum ServerEvent {
+    ChatCreated(ChatResponse),
+    ChatUpdated(ChatResponse),
+    MembershipChanged(MembershipChange),
+    JoinRequested(JoinRequestResponse),
+    ReadCursorUpdated(ReadCursorResponse),
+    MessageEdited(MessageResponse),
+    MessageDeleted(MessageResponse),
+    ReactionAdded(ReactionChange),
+    ReactionRemoved(ReactionChange),
+}
+
+impl ServerEvent {
+    /// Whether the event takes `user_id` out of `chat_id`, so their sockets
+    /// for it must stop receiving its messages.
+    fn ends_membership(&self, user_id: Uuid, chat_id: Uuid) -> bool {
+        match self {
+            ServerEvent::MembershipChanged(change) => {
+                change.user_id == user_id
+                    && change.chat_id == chat_id
+                    && matches!(change.action, MembershipAction::Removed | MembershipAction::Left)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// A `ServerEvent` and who should get it. It is delivered on the user
+/// sockets (`/ws`) those users have open.
+#[derive(Debug, Clone)]
+pub struct UserEvent {
+    pub user_ids: Vec<Uuid>,
+    pub event: ServerEvent,
+}
+
+/// Pushes an event to the users' open sockets.
+pub fn notify_users(state: &AppState, user_ids: Vec<Uuid>, event: ServerEvent) {
+    // Err just means no socket is currently listening
+    let _ = state.user_events_tx.send(UserEvent { user_ids, event });
+}
+
+/// A `ServerEvent` for the sockets open for one chat, e.g. an edit to one of
+/// its messages.
+#[derive(Debug, Clone)]
+pub struct ChatEvent {
+    pub chat_id: Uuid,
+    pub event: ServerEvent,
+}
+
+/// Pushes an event to the sockets open for the chat.
+pub fn notify_chat(state: &AppState, chat_id: Uuid, event: ServerEvent) {
+    // Err just means no socket is currently listening
+    let _ = state.chat_events_tx.send(ChatEvent { chat_id, event });
+}
+
+/// Subprotocol that marks the next `Sec-WebSocket-Protocol` entry as an
+/// access token, e.g. `new WebSocket(url, ["bearer", token])` in a browser.
+const BEARER_PROTOCOL: &str = "bearer";
+
 #[derive(Debug, Deserialize)]
 pub struct WebSocketQuery {
-    pub token: Option<String>,
+    pub ticket: Option<String>,
 }
 
+/// Opens a chat socket. Clients authenticate with a ticket from
+/// `POST /api/ws/ticket` or by passing their access token as a subprotocol,
+/// so the token never ends up in request logs.
 pub async fn websocket_handler(
     ws: WebSocketUpgrade,
     State(state): State<AppState>,
     Path(chat_id): Path<Uuid>,
     Query(params): Query<WebSocketQuery>,
+    headers: HeaderMap,
 ) -> Result<Response, StatusCode> {
-    // Authenticate user from token
-    let token = params.token.ok_or(StatusCode::UNAUTHORIZED)?;
-    let claims = verify_token(&token).map_err(|_| StatusCode::UNAUTHORIZED)?;
-    let user_id = Uuid::parse_str(&claims.sub).map_err(|_| StatusCode::UNAUTHORIZED)?;
+    let (user_id, session_id) = authenticate_socket(&state, params, &headers).await?;
 
     // Verify user is part of the chat
     let is_participant = sqlx::query_scalar!(
@@ -62,12 +135,88 @@ pub async fn websocket_handler(
 
     info!("User {} connected to chat {}", user_id, chat_id);
 
-    Ok(ws.on_upgrade(move |socket| handle_socket(socket, state, chat_id, user_id)))
+    // Browsers drop the connection unless the server picks one of the offered protocols
+    Ok(ws
+        .protocols([BEARER_PROTOCOL])
+        .on_upgrade(move |socket| handle_socket(socket, state, chat_id, user_id, session_id)))
+}
+
+/// Opens the user's own socket, which gets the events addressed to them
+/// rather than to one chat, e.g. being added to a new chat. Authenticates
+/// like `websocket_handler`.
+pub async fn user_websocket_handler(
+    ws: WebSocketUpgrade,
+    State(state): State<AppState>,
+    Query(params): Query<WebSocketQuery>,
+    headers: HeaderMap,
+) -> Result<Response, StatusCode> {
+    let (user_id, session_id) = authenticate_socket(&state, params, &headers).await?;
+
+    // Update user's online status
+    let _ = sqlx::query!(
+        "UPDATE users SET is_online = true, last_seen = NOW() WHERE id = $1",
+        user_id
+    )
+    .execute(state.db.pool())
+    .await;
+
+    info!("User {} connected to their user socket", user_id);
+
+    Ok(ws
+        .protocols([BEARER_PROTOCOL])
+        .on_upgrade(move |socket| handle_user_socket(socket, state, user_id, session_id)))
+}
+
+/// Resolves the ticket or bearer token a socket was opened with to its user
+/// and session, which must still be live.
+async fn authenticate_socket(
+    state: &AppState,
+    params: WebSocketQuery,
+    headers: &HeaderMap,
+) -> Result<(Uuid, Uuid), StatusCode> {
+    let (user_id, session_id) = if let Some(ticket) = params.ticket {
+        tickets::redeem_ticket(state, &ticket)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+            .ok_or(StatusCode::UNAUTHORIZED)?
+    } else {
+        let token = bearer_protocol_token(headers).ok_or(StatusCode::UNAUTHORIZED)?;
+        let claims = verify_token(&state.jwt_keys, token).map_err(|_| StatusCode::UNAUTHORIZED)?;
+        let user_id = Uuid::parse_str(&claims.sub).map_err(|_| StatusCode::UNAUTHORIZED)?;
+        let session_id = Uuid::parse_str(&claims.sid).map_err(|_| StatusCode::UNAUTHORIZED)?;
+        (user_id, session_id)
+    };
+
+    let active = sessions::touch_session(state, user_id, session_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if !active {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+
+    Ok((user_id, session_id))
 }
 
-async fn handle_socket(socket: WebSocket, state: AppState, chat_id: Uuid, user_id: Uuid) {
+/// Finds the token that follows `bearer` in `Sec-WebSocket-Protocol`.
+fn bearer_protocol_token(headers: &HeaderMap) -> Option<&str> {
+    let protocols = headers.get(SEC_WEBSOCKET_PROTOCOL)?.to_str().ok()?;
+    let mut protocols = protocols.split(',').map(str::trim);
+    protocols.find(|protocol| *protocol == BEARER_PROTOCOL)?;
+    protocols.next()
+}
+
+async fn handle_socket(
+    socket: WebSocket,
+    state: AppState,
+    chat_id: Uuid,
+    user_id: Uuid,
+    session_id: Uuid,
+) {
     let (mut sender, mut receiver) = socket.split();
     let mut rx = state.broadcast_tx.subscribe();
+    let mut revoked_rx = state.session_revoked_tx.subscribe();
+    let mut user_events_rx = state.user_events_tx.subscribe();
+    let mut chat_events_rx = state.chat_events_tx.subscribe();
 
     // Send connection confirmation
     let welcome_msg = serde_json::json!({
@@ -85,13 +234,11 @@ async fn handle_socket(socket: WebSocket, state: AppState, chat_id: Uuid, user_i
     }
 
     // Spawn task to handle incoming messages from the client
-    let tx_clone = state.broadcast_tx.clone();
-    let state_clone = state.clone();
     let mut send_task = tokio::spawn(async move {
         while let Some(msg) = receiver.next().await {
             match msg {
                 Ok(Message::Text(text)) => {
-                    if let Err(e) = handle_client_message(&state_clone, &tx_clone, chat_id, user_id, &text).await {
+                    if let Err(e) = handle_client_message(chat_id, user_id, &text).await {
                         error!("Error handling client message: {}", e);
                         break;
                     }
@@ -109,22 +256,73 @@ async fn handle_socket(socket: WebSocket, state: AppState, chat_id: Uuid, user_i
         }
     });
 
-    // Spawn task to handle broadcast messages
+    // Spawn task to handle broadcast messages, user events and session revocation
+    let state_recv = state.clone();
     let mut recv_task = tokio::spawn(async move {
-        while let Ok(chat_message) = rx.recv().await {
-            // Only send messages for this chat
-            if chat_message.chat_id == chat_id {
-                let msg = serde_json::json!({
-                    "type": "message",
-                    "data": chat_message.message
-                });
-
-                if sender
-                    .send(Message::Text(msg.to_string()))
-                    .await
-                    .is_err()
-                {
-                    break;
+        loop {
+            tokio::select! {
+                chat_message = rx.recv() => {
+                    let Ok(chat_message) = chat_message else { break };
+
+                    // Only send messages for this chat
+                    if chat_message.chat_id == chat_id {
+                        let msg = serde_json::json!({
+                            "type": "message",
+                            "data": chat_message.message
+                        });
+
+                        if sender
+                            .send(Message::Text(msg.to_string()))
+                            .await
+                            .is_err()
+                        {
+                            break;
+                        }
+                    }
+                }
+                // User events go to the user socket; a chat socket only
+                // watches for its user leaving the chat
+                user_event = user_events_rx.recv() => match user_event {
+                    Ok(user_event)
+                        if user_event.user_ids.contains(&user_id) && user_event.event.ends_membership(user_id, chat_id) =>
+                    {
+                        info!("User {} left chat {}, closing WebSocket", user_id, chat_id);
+                        let _ = sender
+                            .send(Message::Close(Some(CloseFrame {
+                                code: close_code::POLICY,
+                                reason: "no longer a member".into(),
+                            })))
+                            .await;
+                        break;
+                    }
+                    Err(RecvError::Closed) => break,
+                    _ => {}
+                },
+                chat_event = chat_events_rx.recv() => match chat_event {
+                    Ok(chat_event) if chat_event.chat_id == chat_id => {
+                        let Ok(msg) = serde_json::to_string(&chat_event.event) else { continue };
+                        if sender.send(Message::Text(msg)).await.is_err() {
+                            break;
+                        }
+                    }
+                    Err(RecvError::Closed) => break,
+                    _ => {}
+                },
+                revoked = revoked_rx.recv() => {
+                    let Some(revoked) = session_revoked(&state_recv, revoked, user_id, session_id).await else {
+                        break;
+                    };
+
+                    if revoked {
+                        info!("Session {} revoked, closing WebSocket for user {}", session_id, user_id);
+                        let _ = sender
+                            .send(Message::Close(Some(CloseFrame {
+                                code: close_code::POLICY,
+                                reason: "session revoked".into(),
+                            })))
+                            .await;
+                        break;
+                    }
                 }
             }
         }
@@ -151,9 +349,92 @@ async fn handle_socket(socket: WebSocket, state: AppState, chat_id: Uuid, user_i
     info!("User {} disconnected from chat {}", user_id, chat_id);
 }
 
-async fn handle_client_message(
+async fn handle_user_socket(socket: WebSocket, state: AppState, user_id: Uuid, session_id: Uuid) {
+    let (mut sender, mut receiver) = socket.split();
+    let mut revoked_rx = state.session_revoked_tx.subscribe();
+    let mut user_events_rx = state.user_events_tx.subscribe();
+
+    let welcome_msg = serde_json::json!({
+        "type": "connected",
+        "user_id": user_id
+    });
+
+    if sender
+        .send(Message::Text(welcome_msg.to_string()))
+        .await
+        .is_err()
+    {
+        return;
+    }
+
+    loop {
+        tokio::select! {
+            // Clients don't send anything on this socket; reading just
+            // notices when it closes
+            msg = receiver.next() => match msg {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                _ => {}
+            },
+            user_event = user_events_rx.recv() => match user_event {
+                Ok(user_event) if user_event.user_ids.contains(&user_id) => {
+                    let Ok(msg) = serde_json::to_string(&user_event.event) else { continue };
+                    if sender.send(Message::Text(msg)).await.is_err() {
+                        break;
+                    }
+                }
+                Err(RecvError::Closed) => break,
+                _ => {}
+            },
+            revoked = revoked_rx.recv() => {
+                let Some(revoked) = session_revoked(&state, revoked, user_id, session_id).await else {
+                    break;
+                };
+
+                if revoked {
+                    info!("Session {} revoked, closing user socket for user {}", session_id, user_id);
+                    let _ = sender
+                        .send(Message::Close(Some(CloseFrame {
+                            code: close_code::POLICY,
+                            reason: "session revoked".into(),
+                        })))
+                        .await;
+                    break;
+                }
+            }
+        }
+    }
+
+    // Update user's offline status
+    let _ = sqlx::query!(
+        "UPDATE users SET is_online = false, last_seen = NOW() WHERE id = $1",
+        user_id
+    )
+    .execute(state.db.pool())
+    .await;
+
+    info!("User {} disconnected from their user socket", user_id);
+}
+
+/// Whether what a socket received from `session_revoked_tx` means its
+/// session is gone. `None` once the channel has closed.
+async fn session_revoked(
     state: &AppState,
-    broadcast_tx: &broadcast::Sender<ChatMessage>,
+    received: Result<Uuid, RecvError>,
+    user_id: Uuid,
+    session_id: Uuid,
+) -> Option<bool> {
+    match received {
+        Ok(revoked_id) => Some(revoked_id == session_id),
+        // Revocations were missed and ours may be among them
+        Err(RecvError::Lagged(_)) => Some(!matches!(
+            sessions::touch_session(state, user_id, session_id).await,
+            Ok(true)
+        )),
+        Err(RecvError::Closed) => None,
+    }
+}
+
+async fn handle_client_message(
     chat_id: Uuid,
     user_id: Uuid,
     text: &str,
@@ -170,13 +451,6 @@ async fn handle_client_message(
     match client_msg.msg_type.as_str() {
         "typing" => {
             // Handle typing indicator
-            let typing_msg = serde_json::json!({
-                "type": "typing",
-                "chat_id": chat_id,
-                "user_id": user_id,
-                "is_typing": true
-            });
-
             // Broadcast typing status (you might want a separate channel for this)
             info!("User {} is typing in chat {}", user_id, chat_id);
         }
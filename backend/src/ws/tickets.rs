@@ -0,0 +1,74 @@
+use anyhow::Result;
+use axum::{
+    extract::{Extension, State},
+    http::StatusCode,
+    Json,
+};
+use chrono::{Duration, Utc};
+use serde::Serialize;
+use serde_json::{json, Value};
+use uuid::Uuid;
+
+use crate::{
+    auth::{generate_token, hash_token, CurrentSession},
+    AppState,
+};
+
+/// Tickets only need to survive the round trip from `POST /api/ws/ticket`
+/// to opening the socket.
+const TICKET_TTL_SECS: i64 = 30;
+
+#[derive(Debug, Serialize)]
+pub struct TicketResponse {
+    pub ticket: String,
+    pub expires_in: i64,
+}
+
+/// Issues a single-use ticket for `GET /ws?ticket=...` or
+/// `GET /ws/:chat_id?ticket=...`. The socket it opens is tied to the caller's
+/// session, so revoking the session still closes it.
+pub async fn create_ticket(
+    State(state): State<AppState>,
+    Extension(user_id): Extension<Uuid>,
+    Extension(CurrentSession(session_id)): Extension<CurrentSession>,
+) -> Result<Json<Value>, StatusCode> {
+    let ticket = generate_token();
+
+    // Tickets that were never redeemed are cleaned up here rather than by a job
+    sqlx::query!("DELETE FROM ws_tickets WHERE expires_at < NOW()")
+        .execute(state.db.pool())
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    sqlx::query!(
+        "INSERT INTO ws_tickets (ticket_hash, user_id, session_id, expires_at) VALUES ($1, $2, $3, $4)",
+        hash_token(&ticket),
+        user_id,
+        session_id,
+        Utc::now() + Duration::seconds(TICKET_TTL_SECS)
+    )
+    .execute(state.db.pool())
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(json!({
+        "success": true,
+        "data": TicketResponse {
+            ticket,
+            expires_in: TICKET_TTL_SECS,
+        }
+    })))
+}
+
+/// Consumes a ticket, returning the user and session it was issued to.
+/// Returns `None` if it is unknown, expired or already used.
+pub async fn redeem_ticket(state: &AppState, ticket: &str) -> Result<Option<(Uuid, Uuid)>> {
+    let row = sqlx::query!(
+        "DELETE FROM ws_tickets WHERE ticket_hash = $1 AND expires_at > NOW() RETURNING user_id, session_id",
+        hash_token(ticket)
+    )
+    .fetch_optional(state.db.pool())
+    .await?;
+
+    Ok(row.map(|row| (row.user_id, row.session_id)))
+}
@@ -2,6 +2,7 @@
 
 # WhatsApp Backend API Test Script
 # Tests all endpoints and WebSocket functionality
+# Logs in as a seeded user, so run `cargo run -- seed` first
 
 set -e
 
@@ -18,7 +19,7 @@ curl -s "$BASE_URL/health" | grep -q "OK" && echo "✅ Health check passed" || e
 echo "🔐 Testing login..."
 LOGIN_RESPONSE=$(curl -s -X POST "$BASE_URL/api/auth/login" \
   -H "Content-Type: application/json" \
-  -d '{"email": "alice@example.com", "password": "password123"}')
+  -d '{"email": "user1@example.com", "password": "password123"}')
 
 echo "Login response: $LOGIN_RESPONSE"
 
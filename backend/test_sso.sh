@@ -0,0 +1,178 @@
+#!/bin/bash
+
+# Single Sign-On Test Script
+# Runs the OpenID Connect authorization-code + PKCE flow against the local
+# mock provider: first login, repeat login, linking to a password account,
+# the 2FA challenge for linked accounts, and the cases that must be refused.
+# Computing TOTP codes needs python3.
+#
+# Needs the mock provider and a server configured for it:
+#   docker compose --profile sso up -d mock-oidc
+#   OIDC_ISSUER_URL=http://localhost:8090/default OIDC_CLIENT_ID=cam-chat OIDC_CLIENT_SECRET=secret cargo run
+
+set -e
+
+BASE_URL="${BASE_URL:-http://localhost:8080}"
+OIDC_ISSUER_URL="${OIDC_ISSUER_URL:-http://localhost:8090/default}"
+RUN_ID="sso-$(date +%s%N)"
+
+echo "🚀 Testing single sign-on..."
+
+if ! curl -sf "$OIDC_ISSUER_URL/.well-known/openid-configuration" > /dev/null; then
+    echo "❌ No OpenID provider at $OIDC_ISSUER_URL; start it with: docker compose --profile sso up -d mock-oidc"
+    exit 1
+fi
+
+# Starts a login, signs in at the mock provider as subject $1 with the JSON
+# claims $2, and sets CODE and STATE from where the provider redirects back
+sign_in_at_provider() {
+    local authorize_response authorization_url redirect
+
+    authorize_response=$(curl -s "$BASE_URL/api/auth/oidc/authorize")
+    authorization_url=$(echo "$authorize_response" | grep -o '"authorization_url":"[^"]*"' | cut -d'"' -f4)
+
+    if [ -z "$authorization_url" ]; then
+        echo "❌ Authorize failed, is the server configured for SSO? $authorize_response"
+        exit 1
+    fi
+
+    echo "$authorization_url" | grep -q "code_challenge_method=S256" || {
+        echo "❌ Authorization URL has no PKCE challenge: $authorization_url"
+        exit 1
+    }
+
+    # The mock provider's login form posts back to the authorization URL
+    redirect=$(curl -s -o /dev/null -w "%{redirect_url}" -X POST "$authorization_url" \
+      --data-urlencode "username=$1" \
+      --data-urlencode "claims=$2")
+
+    CODE=$(echo "$redirect" | sed -n 's/.*[?&]code=\([^&]*\).*/\1/p')
+    STATE=$(echo "$redirect" | sed -n 's/.*[?&]state=\([^&]*\).*/\1/p')
+
+    if [ -z "$CODE" ] || [ -z "$STATE" ]; then
+        echo "❌ Provider didn't redirect back with a code: $redirect"
+        exit 1
+    fi
+}
+
+# Posts CODE and STATE to the callback, printing the status and body
+callback() {
+    curl -s -w "\n%{http_code}" -X POST "$BASE_URL/api/auth/oidc/callback" \
+      -H "Content-Type: application/json" \
+      -d "{\"code\": \"$CODE\", \"state\": \"$STATE\"}"
+}
+
+user_id_of() {
+    echo "$1" | grep -o '"user_id":"[^"]*"' | cut -d'"' -f4
+}
+
+# Prints the current TOTP code for the base32 secret $1
+totp_code() {
+    python3 - "$1" <<'PY'
+import base64, hmac, struct, sys, time
+secret = sys.argv[1]
+key = base64.b32decode(secret + "=" * (-len(secret) % 8))
+digest = hmac.new(key, struct.pack(">Q", int(time.time()) // 30), "sha1").digest()
+offset = digest[-1] & 15
+print("%06d" % ((struct.unpack(">I", digest[offset:offset + 4])[0] & 0x7fffffff) % 1000000))
+PY
+}
+
+# Test 1: First login creates an account
+echo "🆕 Testing first login..."
+sign_in_at_provider "$RUN_ID-new" "{\"email\": \"$RUN_ID-new@example.com\", \"email_verified\": true, \"name\": \"SSO User\"}"
+RESPONSE=$(callback)
+NEW_USER_ID=$(user_id_of "$RESPONSE")
+TOKEN=$(echo "$RESPONSE" | grep -o '"token":"[^"]*"' | cut -d'"' -f4)
+
+if [ -n "$NEW_USER_ID" ] && [ -n "$TOKEN" ]; then
+    echo "✅ Logged in as new user $NEW_USER_ID"
+else
+    echo "❌ First login failed: $RESPONSE"
+    exit 1
+fi
+
+curl -s "$BASE_URL/api/chats" -H "Authorization: Bearer $TOKEN" | grep -q '"success":true' \
+  && echo "✅ Token works for the API" || { echo "❌ Token rejected by the API"; exit 1; }
+
+# Test 2: The code and state can only be used once
+echo "🔁 Testing callback replay..."
+STATUS=$(callback | tail -n1)
+if [ "$STATUS" = "400" ] || [ "$STATUS" = "401" ]; then
+    echo "✅ Replayed callback refused ($STATUS)"
+else
+    echo "❌ Replayed callback got $STATUS"
+    exit 1
+fi
+
+# Test 3: Logging in again with the same provider account finds the same user
+echo "🔐 Testing repeat login..."
+sign_in_at_provider "$RUN_ID-new" "{\"email\": \"$RUN_ID-new@example.com\", \"email_verified\": true}"
+RESPONSE=$(callback)
+if [ "$(user_id_of "$RESPONSE")" = "$NEW_USER_ID" ]; then
+    echo "✅ Same user on repeat login"
+else
+    echo "❌ Repeat login gave a different user: $RESPONSE"
+    exit 1
+fi
+
+# Test 4: A verified provider email links to the password account with that email
+echo "🔗 Testing account linking..."
+REGISTER_RESPONSE=$(curl -s -X POST "$BASE_URL/api/auth/register" \
+  -H "Content-Type: application/json" \
+  -d "{\"email\": \"$RUN_ID-linked@example.com\", \"name\": \"Linked User\", \"password\": \"password123\"}")
+LINKED_USER_ID=$(user_id_of "$REGISTER_RESPONSE")
+
+if [ -z "$LINKED_USER_ID" ]; then
+    echo "❌ Registration failed: $REGISTER_RESPONSE"
+    exit 1
+fi
+
+sign_in_at_provider "$RUN_ID-linked" "{\"email\": \"$RUN_ID-linked@example.com\", \"email_verified\": true}"
+RESPONSE=$(callback)
+if [ "$(user_id_of "$RESPONSE")" = "$LINKED_USER_ID" ]; then
+    echo "✅ Provider account linked to the existing user"
+else
+    echo "❌ Linking failed: $RESPONSE"
+    exit 1
+fi
+
+curl -s -X POST "$BASE_URL/api/auth/login" \
+  -H "Content-Type: application/json" \
+  -d "{\"email\": \"$RUN_ID-linked@example.com\", \"password\": \"password123\"}" | grep -q '"token"' \
+  && echo "✅ Password login still works after linking" || { echo "❌ Password login broke after linking"; exit 1; }
+
+# Test 5: Unverified emails are neither linked nor used for new accounts
+echo "🚫 Testing unverified email..."
+sign_in_at_provider "$RUN_ID-unverified" "{\"email\": \"$RUN_ID-linked@example.com\", \"email_verified\": false}"
+STATUS=$(callback | tail -n1)
+if [ "$STATUS" = "403" ]; then
+    echo "✅ Unverified email refused"
+else
+    echo "❌ Unverified email got $STATUS"
+    exit 1
+fi
+
+# Test 6: Linked accounts with 2FA get the same challenge as a password login
+echo "🔒 Testing 2FA on a linked account..."
+PASSWORD_TOKEN=$(curl -s -X POST "$BASE_URL/api/auth/login" \
+  -H "Content-Type: application/json" \
+  -d "{\"email\": \"$RUN_ID-linked@example.com\", \"password\": \"password123\"}" | grep -o '"token":"[^"]*"' | cut -d'"' -f4)
+SECRET=$(curl -s -X POST "$BASE_URL/api/auth/2fa/enroll" -H "Authorization: Bearer $PASSWORD_TOKEN" \
+  | grep -o '"secret":"[^"]*"' | cut -d'"' -f4)
+curl -s -X POST "$BASE_URL/api/auth/2fa/confirm" \
+  -H "Authorization: Bearer $PASSWORD_TOKEN" \
+  -H "Content-Type: application/json" \
+  -d "{\"code\": \"$(totp_code "$SECRET")\"}" | grep -q '"recovery_codes"' \
+  || { echo "❌ Enabling 2FA failed"; exit 1; }
+
+sign_in_at_provider "$RUN_ID-linked" "{\"email\": \"$RUN_ID-linked@example.com\", \"email_verified\": true}"
+RESPONSE=$(callback)
+if echo "$RESPONSE" | grep -q '"two_factor_required":true' && ! echo "$RESPONSE" | grep -q '"token"'; then
+    echo "✅ SSO login asks for the second factor"
+else
+    echo "❌ SSO login skipped 2FA: $RESPONSE"
+    exit 1
+fi
+
+echo "✅ Single sign-on tests complete"
@@ -145,7 +145,7 @@ export const ChatProvider = ({ children }) => {
   const loadChats = async () => {
     try {
       const data = await chats.list();
-      setChatList(data);
+      setChatList(data.chats);
       setLoading(false);
     } catch (error) {
       console.error('Failed to load chats:', error);
@@ -156,7 +156,8 @@ export const ChatProvider = ({ children }) => {
   const loadMessages = async (chatId) => {
     try {
       const data = await chats.getMessages(chatId);
-      setMessages(data);
+      // Pages come newest first; the chat shows them oldest first
+      setMessages([...data.messages].reverse());
     } catch (error) {
       console.error('Failed to load messages:', error);
     }
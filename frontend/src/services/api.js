@@ -46,18 +46,23 @@ export const auth = {
 };
 
 export const chats = {
-  list: async () => {
-    const response = await api.get('/api/chats');
-    return response.data;
+  list: async ({ cursor, limit } = {}) => {
+    const response = await api.get('/api/chats', {
+      params: { cursor, limit },
+    });
+    return {
+      chats: response.data.data.chats,
+      nextCursor: response.data.data.next_cursor,
+    };
   },
-  getMessages: async (chatId, { page = 1, limit = 50 } = {}) => {
+  getMessages: async (chatId, { before, limit = 50 } = {}) => {
     const response = await api.get(`/api/chats/${chatId}/messages`, {
-      params: { page, limit },
+      params: { before, limit },
     });
     return {
-      messages: response.data.messages,
-      hasMore: response.data.has_more,
-      total: response.data.total,
+      messages: response.data.data.messages,
+      hasMoreBefore: response.data.data.has_more_before,
+      hasMoreAfter: response.data.data.has_more_after,
     };
   },
   sendMessage: async (chatId, content) => {
@@ -22,9 +22,10 @@ class WebSocketService {
       return;
     }
 
-    const wsUrl = `${process.env.REACT_APP_BACKEND_URL?.replace('http', 'ws') || 'ws://localhost:8080'}/ws/${chatId}?token=${token}`;
+    const wsUrl = `${process.env.REACT_APP_BACKEND_URL?.replace('http', 'ws') || 'ws://localhost:8080'}/ws/${chatId}`;
     
-    this.ws = new WebSocket(wsUrl);
+    // Pass the token as a subprotocol so it stays out of the URL (and server logs)
+    this.ws = new WebSocket(wsUrl, ['bearer', token]);
 
     this.ws.onmessage = (event) => {
       const data = JSON.parse(event.data);